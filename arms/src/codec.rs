@@ -0,0 +1,193 @@
+use std::io::{Error, ErrorKind};
+
+use libz_sys::{uLongf, Bytef, Z_DEFAULT_COMPRESSION, Z_OK};
+
+use crate::{TagSection, TagValue};
+
+/// The name of the file-level tag that records how the chunk bodies are
+/// compressed. Files without it are not compressed.
+pub const CODEC_TAG: &str = "codec";
+
+/// How the records of each chunk are stored.
+///
+/// Compressed chunks keep the `nbytes` and `nrec` header, with `nbytes`
+/// counting the bytes in the file, followed by the u32 size of the
+/// uncompressed records and the compressed records. Each chunk is
+/// compressed independently, so chunks can still be copied, skipped and
+/// decoded on their own.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Codec {
+    None,
+    Zlib,
+}
+
+// deflate cannot compress by more than about 1032:1, so chunks declaring a
+// larger uncompressed size are corrupt
+const MAX_ZLIB_RATIO: usize = 1032;
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+impl Codec {
+    /// The value of the `codec` file-level tag.
+    pub fn id(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zlib => 1,
+        }
+    }
+
+    pub fn from_id(id: u64) -> std::io::Result<Codec> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zlib),
+            _ => Err(invalid_data(format!("unknown chunk codec {}", id))),
+        }
+    }
+
+    /// The codec recorded in the file-level tags of a RAD file.
+    pub fn from_file_tags(tags: &TagSection, values: &[TagValue]) -> std::io::Result<Codec> {
+        match tags.position(CODEC_TAG).map(|idx| values[idx].as_u64()) {
+            None => Ok(Codec::None),
+            Some(Some(id)) => Codec::from_id(id),
+            Some(None) => Err(invalid_data(format!(
+                "the {} file-level tag is not an integer",
+                CODEC_TAG
+            ))),
+        }
+    }
+
+    /// Compress the records of a chunk.
+    pub fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zlib => {
+                let mut out = Vec::with_capacity(4 + data.len() / 2);
+                out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                let bound = unsafe { libz_sys::compressBound(data.len() as uLongf) };
+                let mut out_len = bound;
+                let mut buf = vec![0u8; bound as usize];
+                let ret = unsafe {
+                    libz_sys::compress2(
+                        buf.as_mut_ptr() as *mut Bytef,
+                        &mut out_len,
+                        data.as_ptr() as *const Bytef,
+                        data.len() as uLongf,
+                        Z_DEFAULT_COMPRESSION,
+                    )
+                };
+                if ret != Z_OK {
+                    return Err(Error::other(format!("zlib compression failed ({})", ret)));
+                }
+                out.extend_from_slice(&buf[..out_len as usize]);
+                Ok(out)
+            }
+        }
+    }
+
+    /// Decompress the records of a chunk compressed by `compress`.
+    pub fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zlib => {
+                if data.len() < 4 {
+                    return Err(invalid_data("compressed chunk is too short".to_string()));
+                }
+                let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                if len > (data.len() - 4).saturating_mul(MAX_ZLIB_RATIO) {
+                    return Err(invalid_data(format!(
+                        "compressed chunk of {} bytes cannot hold {} bytes",
+                        data.len(),
+                        len
+                    )));
+                }
+                let mut out = vec![0u8; len];
+                let mut out_len = len as uLongf;
+                let ret = unsafe {
+                    libz_sys::uncompress(
+                        out.as_mut_ptr() as *mut Bytef,
+                        &mut out_len,
+                        data[4..].as_ptr() as *const Bytef,
+                        (data.len() - 4) as uLongf,
+                    )
+                };
+                if ret != Z_OK || out_len as usize != len {
+                    return Err(invalid_data(format!("couldn't decompress chunk ({})", ret)));
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::RadIndex;
+    use crate::test_util::TestRad;
+    use crate::{RADType, RadReader, RadWriter};
+    use std::io::Cursor;
+
+    fn write_rad(codec: Codec) -> (Vec<u8>, RadIndex) {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .file_tag(CODEC_TAG, RADType::U8, TagValue::U8(codec.id()))
+            .writer(&mut out)
+            .chunk_records(100);
+        for i in 0..250 {
+            writer
+                .write_read(i / 10, i as u64 % 7, &[0x80000000, 1])
+                .unwrap();
+        }
+        let index = writer.finish_with_index().unwrap();
+        (out.into_inner(), index)
+    }
+
+    fn reads_of(bytes: Vec<u8>) -> Vec<(u128, u64, Vec<u32>)> {
+        RadReader::new(Cursor::new(bytes))
+            .unwrap()
+            .flat_map(|c| c.unwrap().reads)
+            .map(|r| (r.bc, r.umi, r.tids))
+            .collect()
+    }
+
+    #[test]
+    fn compressed_chunks_round_trip() {
+        let (plain, _) = write_rad(Codec::None);
+        let (compressed, index) = write_rad(Codec::Zlib);
+        assert!(compressed.len() < plain.len() / 2);
+        assert_eq!(reads_of(compressed.clone()), reads_of(plain));
+
+        let rdr = RadReader::new(Cursor::new(compressed.clone())).unwrap();
+        assert_eq!(rdr.codec(), Codec::Zlib);
+        assert_eq!(RadIndex::build(rdr).unwrap(), index);
+        assert_eq!((index.chunks[1].min_bc, index.chunks[1].max_bc), (10, 19));
+
+        // chunks are copied without being recompressed
+        let mut rdr = RadReader::new(Cursor::new(compressed.clone())).unwrap();
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = RadWriter::with_header_of(&mut out, &rdr).unwrap();
+        while let Some(raw) = rdr.next_raw_chunk().unwrap() {
+            writer.write_raw_chunk(&raw).unwrap();
+        }
+        assert_eq!(writer.finish_with_index().unwrap(), index);
+        assert_eq!(out.into_inner(), compressed);
+    }
+
+    #[test]
+    fn rejects_corrupt_chunks() {
+        let data = Codec::Zlib.compress(b"some records").unwrap();
+        assert_eq!(Codec::Zlib.decompress(&data).unwrap(), b"some records");
+        let mut bad = data.clone();
+        bad[0] += 1;
+        assert!(Codec::Zlib.decompress(&bad).is_err());
+        assert!(Codec::Zlib.decompress(&data[..data.len() - 2]).is_err());
+        // a declared size zlib cannot reach is rejected before allocating it
+        let mut huge = data.clone();
+        huge[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let e = Codec::Zlib.decompress(&huge).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(Codec::from_id(7).is_err());
+    }
+}
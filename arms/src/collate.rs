@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::reader::ReadRecord;
+use crate::subset::parse_barcode;
+use crate::{RadReader, RadWriter};
+
+/// Options of `collate_rad`.
+#[derive(Clone, Debug)]
+pub struct CollateOpts {
+    /// the number of reads to hold in memory at once; cells with more
+    /// reads than this are still read into memory as a whole
+    pub max_records: u64,
+    /// the number of cells to write to each chunk
+    pub cells_per_chunk: usize,
+    /// the directory for the temporary files
+    pub tmp_dir: PathBuf,
+    /// only keep reads of these barcodes (after correction)
+    pub permit: Option<HashSet<u128>>,
+    /// the corrected barcode of each barcode to be corrected
+    pub corrections: Option<HashMap<u128, u128>>,
+}
+
+impl Default for CollateOpts {
+    fn default() -> Self {
+        CollateOpts {
+            max_records: 10_000_000,
+            cells_per_chunk: 1,
+            tmp_dir: std::env::temp_dir(),
+            permit: None,
+            corrections: None,
+        }
+    }
+}
+
+/// What `collate_rad` did with the reads.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CollateSummary {
+    pub num_cells: u64,
+    pub num_reads: u64,
+    /// reads whose barcode was replaced by its correction
+    pub num_corrected: u64,
+    /// reads dropped because their barcode is not in the permit list
+    pub num_dropped: u64,
+    pub num_chunks: u64,
+}
+
+/// Read a barcode correction map with the barcode to be corrected and its
+/// correction in the first two whitespace-separated fields of each line,
+/// as in alevin-fry's `permit_map` output decoded to sequences.
+pub fn read_barcode_map<P: AsRef<Path>>(
+    path: P,
+    cblen: Option<usize>,
+) -> std::io::Result<HashMap<u128, u128>> {
+    let rdr = BufReader::new(File::open(path)?);
+    let mut corrections = HashMap::new();
+    for (lnum, line) in rdr.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        let pair = match (fields.next(), fields.next()) {
+            (Some(from), Some(to)) => parse_barcode(from, cblen)
+                .and_then(|from| parse_barcode(to, cblen).map(|to| (from, to))),
+            (Some(_), None) => Err("expected a barcode and its correction".to_string()),
+            (None, _) => continue,
+        };
+        let (from, to) = pair.map_err(|msg| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("line {} of the barcode map: {}", lnum + 1, msg),
+            )
+        })?;
+        corrections.insert(from, to);
+    }
+    Ok(corrections)
+}
+
+// tells the temporary files of concurrent runs apart
+static COLLATE_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+// temporary bucket files, removed when dropped
+struct Buckets {
+    paths: Vec<PathBuf>,
+}
+
+impl Drop for Buckets {
+    fn drop(&mut self) {
+        for p in self.paths.iter() {
+            let _ = std::fs::remove_file(p);
+        }
+    }
+}
+
+/// Group the reads of the RAD file at `rad_file` by barcode, writing them to
+/// `owriter` in the order of the packed barcodes, with all reads of a cell in
+/// the same chunk. The reads of a cell keep their order.
+///
+/// The reads are first counted per barcode, then distributed over temporary
+/// files, each holding a range of barcodes with at most `max_records` reads,
+/// which are finally sorted in memory one after the other.
+pub fn collate_rad<P: AsRef<Path>, W: Write + Seek>(
+    rad_file: P,
+    owriter: W,
+    opts: &CollateOpts,
+) -> std::io::Result<CollateSummary> {
+    let mut summary = CollateSummary::default();
+    let correct = |rec: &mut ReadRecord, summary: &mut CollateSummary| -> bool {
+        if let Some(to) = opts.corrections.as_ref().and_then(|c| c.get(&rec.bc)) {
+            if *to != rec.bc {
+                rec.bc = *to;
+                summary.num_corrected += 1;
+            }
+        }
+        match opts.permit {
+            Some(ref permit) if !permit.contains(&rec.bc) => {
+                summary.num_dropped += 1;
+                false
+            }
+            _ => true,
+        }
+    };
+
+    // count the reads of each cell
+    let mut rdr = RadReader::from_path(&rad_file)?;
+    let mut counts: HashMap<u128, u64> = HashMap::new();
+    while let Some(chunk) = rdr.next_chunk()? {
+        for mut rec in chunk.reads.into_iter() {
+            summary.num_reads += 1;
+            if correct(&mut rec, &mut summary) {
+                *counts.entry(rec.bc).or_insert(0) += 1;
+            }
+        }
+    }
+    summary.num_cells = counts.len() as u64;
+
+    // the smallest barcode of each bucket
+    let mut cells: Vec<(u128, u64)> = counts.into_iter().collect();
+    cells.sort_unstable();
+    let mut bucket_starts = Vec::new();
+    let mut in_bucket = 0;
+    for (bc, n) in cells.iter() {
+        if bucket_starts.is_empty() || in_bucket + n > opts.max_records {
+            bucket_starts.push(*bc);
+            in_bucket = 0;
+        }
+        in_bucket += n;
+    }
+    drop(cells);
+
+    // distribute the reads over the buckets
+    let mut rdr = RadReader::from_path(&rad_file)?;
+    let run = COLLATE_RUNS.fetch_add(1, Ordering::SeqCst);
+    let buckets = Buckets {
+        paths: (0..bucket_starts.len())
+            .map(|k| {
+                opts.tmp_dir.join(format!(
+                    "fishgill-collate-{}-{}-{}.rad",
+                    std::process::id(),
+                    run,
+                    k
+                ))
+            })
+            .collect(),
+    };
+    let mut bucket_writers = Vec::with_capacity(buckets.paths.len());
+    for p in buckets.paths.iter() {
+        let w = BufWriter::new(File::create(p)?);
+        bucket_writers.push(RadWriter::with_header_of(w, &rdr)?.chunk_records(1000));
+    }
+    let mut ignored = CollateSummary::default();
+    while let Some(chunk) = rdr.next_chunk()? {
+        for mut rec in chunk.reads.into_iter() {
+            if correct(&mut rec, &mut ignored) {
+                let k = match bucket_starts.binary_search(&rec.bc) {
+                    Ok(k) => k,
+                    Err(k) => k - 1,
+                };
+                bucket_writers[k].write_record(&rec)?;
+            }
+        }
+    }
+    for w in bucket_writers.into_iter() {
+        w.finish()?;
+    }
+
+    // sort each bucket and write one chunk per group of cells
+    let mut writer = RadWriter::with_header_of(owriter, &rdr)?.chunk_records(u32::MAX);
+    let cells_per_chunk = opts.cells_per_chunk.max(1);
+    let mut cells_in_chunk = 0;
+    for p in buckets.paths.iter() {
+        let mut reads: Vec<ReadRecord> = Vec::new();
+        for chunk in RadReader::from_path(p)? {
+            reads.extend(chunk?.reads);
+        }
+        reads.sort_by_key(|r| r.bc);
+        for (i, rec) in reads.iter().enumerate() {
+            writer.write_record(rec)?;
+            if reads.get(i + 1).map(|r| r.bc) != Some(rec.bc) {
+                cells_in_chunk += 1;
+                if cells_in_chunk == cells_per_chunk {
+                    writer.flush_chunk()?;
+                    cells_in_chunk = 0;
+                }
+            }
+        }
+        std::fs::remove_file(p)?;
+    }
+    summary.num_chunks = writer.finish()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempPath, TestRad};
+    use crate::RADType;
+    use std::io::Cursor;
+
+    // reads of barcodes 5, 3, 1, 5, 3, ... in BAM order, the UMI counting up
+    fn write_rad(name: &str) -> TempPath {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .lengths(RADType::U8, 4, RADType::U8, 4)
+            .writer(&mut out)
+            .chunk_records(4);
+        for i in 0..12 {
+            writer
+                .write_read(5 - 2 * (i as u128 % 3), i, &[0x80000000])
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let path = TempPath::new(&format!("collate-{}-in.rad", name));
+        std::fs::write(&path, out.into_inner()).unwrap();
+        path
+    }
+
+    fn chunks_of(bytes: Vec<u8>) -> Vec<Vec<(u128, u64)>> {
+        RadReader::new(Cursor::new(bytes))
+            .unwrap()
+            .map(|c| c.unwrap().reads.iter().map(|r| (r.bc, r.umi)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn groups_reads_by_barcode() {
+        let path = write_rad("group");
+        // at most 5 reads in memory, i.e. one cell per bucket
+        let opts = CollateOpts {
+            max_records: 5,
+            ..Default::default()
+        };
+        let mut out = Cursor::new(Vec::new());
+        let summary = collate_rad(&path, &mut out, &opts).unwrap();
+        assert_eq!(summary.num_cells, 3);
+        assert_eq!(summary.num_chunks, 3);
+        assert_eq!(
+            chunks_of(out.into_inner()),
+            vec![
+                vec![(1, 2), (1, 5), (1, 8), (1, 11)],
+                vec![(3, 1), (3, 4), (3, 7), (3, 10)],
+                vec![(5, 0), (5, 3), (5, 6), (5, 9)],
+            ]
+        );
+    }
+
+    #[test]
+    fn corrects_and_filters_barcodes() {
+        let path = write_rad("permit");
+        let opts = CollateOpts {
+            cells_per_chunk: 2,
+            permit: Some([5, 7].iter().cloned().collect()),
+            corrections: Some([(3, 7)].iter().cloned().collect()),
+            ..Default::default()
+        };
+        let mut out = Cursor::new(Vec::new());
+        let summary = collate_rad(&path, &mut out, &opts).unwrap();
+        assert_eq!(summary.num_reads, 12);
+        assert_eq!(summary.num_corrected, 4);
+        assert_eq!(summary.num_dropped, 4);
+        assert_eq!(summary.num_cells, 2);
+
+        let chunks = chunks_of(out.into_inner());
+        assert_eq!(chunks.len(), 1);
+        let bcs: Vec<u128> = chunks[0].iter().map(|r| r.0).collect();
+        assert_eq!(bcs, vec![5, 5, 5, 5, 7, 7, 7, 7]);
+    }
+
+    #[test]
+    fn reads_barcode_map() {
+        let path = TempPath::new("collate-map.tsv");
+        std::fs::write(&path, "AAAC\tAAAG\nAAAT AAAT\n").unwrap();
+        let map = read_barcode_map(&path, Some(4)).unwrap();
+        assert_eq!(map, [(1, 2), (3, 3)].iter().cloned().collect());
+
+        std::fs::write(&path, "AAAC\n").unwrap();
+        assert!(read_barcode_map(&path, Some(4)).is_err());
+    }
+}
@@ -4,15 +4,20 @@ extern crate slog;
 extern crate num;
 
 use self::indicatif::{ProgressBar, ProgressStyle};
-use self::slog::{crit, info};
+use self::slog::{info, warn};
 use std::fs;
 use std::fs::File;
-use std::io::{Write, Cursor, Seek, SeekFrom, BufWriter};
-use rust_htslib::{bam, bam::Read};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use rust_htslib::{bam, bam::record::Aux, bam::Read};
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::time::Instant;
 use crate as arms;
+use crate::subset::read_barcode_list;
 
 
 
@@ -26,203 +31,763 @@ use crate as arms;
 // https://github.com/k3yavi/flash/blob/master/src-rs/src/fragments.rs#L162-L176
 // https://github.com/COMBINE-lab/salmon/blob/master/src/AlevinUtils.cpp#L789
 pub fn cb_string_to_u64(cb_str: &[u8]) -> Result<u64, Box<dyn Error>> {
-    let mut cb_id: u64 = 0;
+    cb_string_to_u64_with(cb_str, AmbiguousBases::Map)
+}
+
+/// What `cb_string_to_u64_with` does with the IUPAC ambiguity codes
+/// (N, R, Y, ...) in a barcode or UMI.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum AmbiguousBases {
+    /// return an error
+    #[default]
+    Reject,
+    /// pack them as the first base they stand for in the order A, C, G,
+    /// T, e.g. N and R as A and Y as C
+    Map,
+}
+
+/// What `bam2rad` does with reads that have N bases in the barcode or UMI.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum NBases {
+    /// skip the read
+    Drop,
+    /// replace a single N by A and skip reads with more Ns
+    #[default]
+    Replace,
+    /// try each base for the Ns of the barcode, up to `MAX_EXPANDED_NS`
+    /// of them, and keep the read if exactly one of the candidates is on
+    /// the whitelist; Ns in UMIs are replaced as with `Replace`
+    Expand,
+}
+
+/// The largest number of Ns in a barcode that `NBases::Expand` resolves.
+pub const MAX_EXPANDED_NS: usize = 3;
+
+// the one barcode on `whitelist` that `bc` stands for with each N replaced
+// by any base, if there is exactly one
+fn expand_ns(bc: &[u8], whitelist: &HashSet<u128>, ambiguous: AmbiguousBases) -> Option<u128> {
+    let ns: Vec<usize> = (0..bc.len()).filter(|&i| bc[i] == b'N').collect();
+    if ns.len() > MAX_EXPANDED_NS {
+        return None;
+    }
+    let mut candidate = bc.to_vec();
+    let mut found = None;
+    for k in 0..(1usize << (2 * ns.len())) {
+        for (i, p) in ns.iter().enumerate() {
+            candidate[*p] = b"ACGT"[(k >> (2 * i)) & 3];
+        }
+        match cb_string_to_u128_with(&candidate, ambiguous) {
+            Ok(packed) if whitelist.contains(&packed) => {
+                if found.is_some() {
+                    return None;
+                }
+                found = Some(packed);
+            }
+            _ => (),
+        }
+    }
+    found
+}
+
+// the 2-bit code of a base, A 00, C 01, G 10, T 11, or of the first base an
+// ambiguity code stands for
+fn base_code(nt: u8, ambiguous: AmbiguousBases) -> Option<u128> {
+    match (nt.to_ascii_uppercase(), ambiguous) {
+        (b'A', _) => Some(0),
+        (b'C', _) => Some(1),
+        (b'G', _) => Some(2),
+        (b'T', _) => Some(3),
+        (b'N', AmbiguousBases::Map)
+        | (b'R', AmbiguousBases::Map)
+        | (b'W', AmbiguousBases::Map)
+        | (b'M', AmbiguousBases::Map)
+        | (b'D', AmbiguousBases::Map)
+        | (b'H', AmbiguousBases::Map)
+        | (b'V', AmbiguousBases::Map) => Some(0),
+        (b'Y', AmbiguousBases::Map) | (b'S', AmbiguousBases::Map) | (b'B', AmbiguousBases::Map) => {
+            Some(1)
+        }
+        (b'K', AmbiguousBases::Map) => Some(2),
+        _ => None,
+    }
+}
+
+/// Pack a barcode or UMI of at most 32 bases into a u64, two bits per
+/// base, in either case. Bases that are not nucleotides, and ambiguity
+/// codes unless `ambiguous` maps them, are an error.
+pub fn cb_string_to_u64_with(
+    cb_str: &[u8],
+    ambiguous: AmbiguousBases,
+) -> Result<u64, Box<dyn Error>> {
+    if cb_str.len() > 32 {
+        return Err(format!("{} bases do not fit into 64 bits", cb_str.len()).into());
+    }
+    cb_string_to_u128_with(cb_str, ambiguous).map(|cb_id| cb_id as u64)
+}
+
+/// Like `cb_string_to_u64_with`, for barcodes of up to 64 bases.
+pub fn cb_string_to_u128_with(
+    cb_str: &[u8],
+    ambiguous: AmbiguousBases,
+) -> Result<u128, Box<dyn Error>> {
+    if cb_str.len() > 64 {
+        return Err(format!("{} bases do not fit into 128 bits", cb_str.len()).into());
+    }
+    let mut cb_id: u128 = 0;
     for (idx, nt) in cb_str.iter().rev().enumerate() {
-        let offset = idx * 2;
-        match nt {
-            65 | 78 => (),              // A | N 00
-            67 => cb_id |= 1 << offset, // C 01
-            71 => cb_id |= 2 << offset, // G 10
-            84 => cb_id |= 3 << offset, // T 11
-            _ => panic!("unknown nucleotide {}", nt),
-        };
+        match base_code(*nt, ambiguous) {
+            Some(code) => cb_id |= code << (idx * 2),
+            None => {
+                return Err(format!(
+                    "cannot encode base {:?} of {:?}",
+                    *nt as char,
+                    String::from_utf8_lossy(cb_str)
+                )
+                .into())
+            }
+        }
     }
 
     Ok(cb_id)
 }
 
+/// Decode a barcode or UMI of length `len` packed by `cb_string_to_u64`
+/// back to its sequence; `len` must be at most 32.
+pub fn u64_to_barcode_string(value: u64, len: usize) -> String {
+    u128_to_barcode_string(value as u128, len)
+}
+
+/// Decode a barcode of length `len` packed by `cb_string_to_u128_with`
+/// back to its sequence; `len` must be at most 64.
+pub fn u128_to_barcode_string(value: u128, len: usize) -> String {
+    (0..len)
+        .map(|idx| {
+            let offset = (len - 1 - idx) * 2;
+            match (value >> offset) & 3 {
+                0 => 'A',
+                1 => 'C',
+                2 => 'G',
+                _ => 'T',
+            }
+        })
+        .collect()
+}
 
-pub fn bam2rad(input_file: String, rad_file: String, num_threads: u32, log: &slog::Logger) {
-    let oname = Path::new(&rad_file);
-    let parent = oname.parent().unwrap();
-    std::fs::create_dir_all(&parent).unwrap();
+/// Options of `bam2rad`; the defaults write unpaired single-cell RAD.
+#[derive(Clone, Debug)]
+pub struct ConvertOpts {
+    /// write paired-end RAD, with one alignment per mate pair
+    pub paired: bool,
+    /// in paired mode, also record the positions of both mates
+    pub mate_positions: bool,
+    /// record the 0-based position of each alignment (`pos`)
+    pub positions: bool,
+    /// record the fragment length of each alignment (`frag_len`)
+    pub frag_len: bool,
+    /// record the distance of each alignment to the 3' end of its
+    /// reference (`dist_3p`)
+    pub dist_3p: bool,
+    /// also write an index of the chunks next to the RAD file
+    pub index: bool,
+    /// compress the records of each chunk with zlib
+    pub compress: bool,
+    /// how to pack ambiguous bases other than a single N in barcodes and
+    /// UMIs; reads whose barcode or UMI cannot be packed are skipped
+    pub ambiguous: AmbiguousBases,
+    /// the tags holding the cell barcode, in order of preference; the
+    /// first one a record has is used
+    pub cb_tags: Vec<String>,
+    /// the tags holding the UMI, in order of preference
+    pub umi_tags: Vec<String>,
+    /// what to do with reads with N bases in the barcode or UMI
+    pub n_bases: NBases,
+    /// the barcodes that `NBases::Expand` resolves Ns against, one per line
+    pub whitelist: Option<PathBuf>,
+    /// only convert records with all of these SAM flags, as `samtools view -f`
+    pub require_flags: u16,
+    /// skip records with any of these SAM flags, as `samtools view -F`
+    pub exclude_flags: u16,
+    /// write each reference and strand at most once per read, counting a
+    /// mate pair as one fragment, with the tags of its first alignment
+    pub dedup_tids: bool,
+    /// sort the alignments of each read by reference, forward first
+    pub sort_tids: bool,
+    /// write a JSON report of the conversion to this file
+    pub report: Option<PathBuf>,
+    /// also write the metadata alevin-fry expects next to the RAD file
+    /// (see `metadata::write_alevin_fry_metadata`)
+    pub alevin_fry: bool,
+}
+
+/// The SAM flag of unmapped records, which `bam2rad` excludes by default.
+pub const FLAG_UNMAPPED: u16 = 0x4;
 
-    if oname.exists() {
-        std::fs::remove_file(oname).expect("could not be deleted");
+impl Default for ConvertOpts {
+    fn default() -> Self {
+        ConvertOpts {
+            paired: false,
+            mate_positions: false,
+            positions: false,
+            frag_len: false,
+            dist_3p: false,
+            index: false,
+            compress: false,
+            ambiguous: AmbiguousBases::default(),
+            cb_tags: vec!["CB".to_string()],
+            umi_tags: vec!["UR".to_string()],
+            n_bases: NBases::default(),
+            whitelist: None,
+            require_flags: 0,
+            exclude_flags: FLAG_UNMAPPED,
+            dedup_tids: false,
+            sort_tids: false,
+            report: None,
+            alevin_fry: false,
+        }
+    }
+}
+
+impl ConvertOpts {
+    /// Check that the options can be used together to write `rad_file`
+    /// ("-" for stdout), before `bam2rad` opens any file.
+    pub fn check(&self, rad_file: &str) -> Result<(), String> {
+        if self.cb_tags.is_empty() || self.umi_tags.is_empty() {
+            return Err("--cb-tag and --umi-tag need at least one tag".to_string());
+        }
+        if let Some(tag) = self
+            .cb_tags
+            .iter()
+            .chain(self.umi_tags.iter())
+            .find(|t| t.len() != 2)
+        {
+            return Err(format!("{:?} is not a SAM tag name", tag));
+        }
+        if self.n_bases == NBases::Expand && self.whitelist.is_none() {
+            return Err("expanding the Ns of barcodes needs a whitelist".to_string());
+        }
+        if self.alevin_fry {
+            // alevin-fry reads uncompressed single-end files whose only
+            // alignment-level tag is the reference id
+            if rad_file == "-" {
+                return Err("the alevin-fry metadata cannot be written for stdout".to_string());
+            }
+            if self.compress {
+                return Err("alevin-fry cannot read compressed RAD files".to_string());
+            }
+            if self.paired {
+                return Err("alevin-fry cannot read paired RAD files".to_string());
+            }
+            if self.positions || self.frag_len || self.dist_3p {
+                return Err("alevin-fry cannot read the position tags".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+// the value of the first of `tags` that `rec` has as a string
+fn first_string_tag<'a>(rec: &'a bam::Record, tags: &[String]) -> Option<&'a [u8]> {
+    tags.iter().find_map(|t| match rec.aux(t.as_bytes()) {
+        Some(Aux::String(s)) => Some(s),
+        _ => None,
+    })
+}
+
+// the most common of the counted lengths, the shorter one on ties
+fn most_common_len(counts: &HashMap<usize, u64>) -> usize {
+    counts
+        .iter()
+        .max_by_key(|(l, n)| (**n, Reverse(**l)))
+        .map_or(0, |(l, _)| *l)
+}
+
+// the number of tagged records whose barcode and UMI lengths are declared
+// in the file-level tags
+const LENGTH_SAMPLE: usize = 1000;
+
+/// What `bam2rad` did with the reads, counting all records of a read once.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConvertSummary {
+    /// the size of the SAM/BAM file
+    pub input_bytes: u64,
+    /// the size of the RAD file, unless it is written to a pipe
+    pub rad_bytes: Option<u64>,
+    /// the SAM/BAM records, counting each record of a read
+    pub num_records: u64,
+    /// the reads of all records, as alevin's `num_processed`
+    pub input_reads: u64,
+    /// the reads with records that are not excluded
+    pub num_reads: u64,
+    /// reads written to the RAD file
+    pub num_written: u64,
+    pub num_chunks: u64,
+    /// reads without any of the barcode tags
+    pub no_barcode: u64,
+    /// reads with a barcode but without any of the UMI tags
+    pub no_umi: u64,
+    /// reads whose barcode or UMI length differs from the declared one
+    pub bad_length: u64,
+    /// reads dropped for the N bases in their barcode or UMI
+    pub dropped_n: u64,
+    /// reads kept with the Ns of their barcode or UMI replaced by A
+    pub replaced_n: u64,
+    /// reads kept with the Ns of their barcode resolved on the whitelist
+    pub expanded_n: u64,
+    /// reads whose barcode or UMI has other bases that cannot be packed
+    pub unpackable: u64,
+    /// records skipped for their SAM flags, or because they have no
+    /// reference, before they are grouped into reads
+    pub excluded_records: u64,
+    /// the excluded records that are unmapped
+    pub unmapped_records: u64,
+    /// alignments merged into an earlier one to the same reference and
+    /// strand with `dedup_tids`
+    pub merged_alignments: u64,
+    /// the declared barcode and UMI lengths
+    pub cblen: u16,
+    pub ulen: u16,
+}
+
+impl ConvertSummary {
+    /// The number of reads skipped for each reason, and the reason.
+    pub fn skipped(&self) -> Vec<(u64, &'static str)> {
+        vec![
+            (self.no_barcode, "without a barcode tag"),
+            (self.no_umi, "without a UMI tag"),
+            (
+                self.bad_length,
+                "whose barcode or UMI length differs from the declared one",
+            ),
+            (self.dropped_n, "for the N bases in their barcode or UMI"),
+            (self.unpackable, "whose barcode or UMI could not be packed"),
+        ]
+    }
+
+    pub fn num_skipped(&self) -> u64 {
+        self.skipped().iter().map(|(n, _)| n).sum()
+    }
+
+    /// The report of the conversion of `input_file` to `rad_file`, which
+    /// took `seconds`.
+    pub fn to_json(&self, input_file: &str, rad_file: &str, seconds: f64) -> Value {
+        json!({
+            "input": {"path": input_file, "bytes": self.input_bytes},
+            "output": {"path": rad_file, "bytes": self.rad_bytes, "num_chunks": self.num_chunks},
+            "num_records": self.num_records,
+            "input_reads": self.input_reads,
+            "excluded_records": self.excluded_records,
+            "unmapped_records": self.unmapped_records,
+            "num_reads": self.num_reads,
+            "num_written": self.num_written,
+            "skipped_reads": {
+                "no_barcode": self.no_barcode,
+                "no_umi": self.no_umi,
+                "bad_length": self.bad_length,
+                "n_bases": self.dropped_n,
+                "unpackable": self.unpackable,
+            },
+            "n_bases": {
+                "dropped": self.dropped_n,
+                "replaced": self.replaced_n,
+                "expanded": self.expanded_n,
+            },
+            "merged_alignments": self.merged_alignments,
+            "cblen": self.cblen,
+            "ulen": self.ulen,
+            "wall_time_s": seconds,
+        })
+    }
+}
+
+// bits of the `mate_ori` alignment-level tag written in paired mode
+pub const MATE1_ALIGNED: u8 = 0x1;
+pub const MATE1_FW: u8 = 0x2;
+pub const MATE2_ALIGNED: u8 = 0x4;
+pub const MATE2_FW: u8 = 0x8;
+
+// the fields of a BAM record we need once all records of a read are seen
+#[derive(Clone, Copy, Debug)]
+struct AlnInfo {
+    tid: i32,
+    pos: i64,
+    mtid: i32,
+    mpos: i64,
+    insert_size: i64,
+    is_reverse: bool,
+    is_paired: bool,
+    is_mate_unmapped: bool,
+    is_first: bool,
+}
+
+impl AlnInfo {
+    fn from_record(rec: &bam::Record) -> AlnInfo {
+        AlnInfo {
+            tid: rec.tid(),
+            pos: rec.pos(),
+            mtid: rec.mtid(),
+            mpos: rec.mpos(),
+            insert_size: rec.insert_size(),
+            is_reverse: rec.is_reverse(),
+            is_paired: rec.is_paired(),
+            is_mate_unmapped: rec.is_mate_unmapped(),
+            is_first: rec.is_first_in_template(),
+        }
     }
-    let ofile = File::create(&rad_file).unwrap();
 
-    let mut bam = bam::Reader::from_path(&input_file).unwrap();
-    let bam_bytes = fs::metadata(&input_file).unwrap().len();
+    // the reference id with the orientation in the highest bit
+    fn compressed_tid(&self) -> u32 {
+        let mut tid = self.tid as u32;
+        if !self.is_reverse {
+            tid |= 0x80000000;
+        }
+        tid
+    }
+
+    fn is_mate_of(&self, other: &AlnInfo) -> bool {
+        self.is_first != other.is_first
+            && self.tid == other.mtid
+            && self.pos == other.mpos
+            && self.mtid == other.tid
+            && self.mpos == other.pos
+    }
+}
+
+// group the records of a paired-end read into fragments of (mate 1, mate 2);
+// a record whose mate is mapped is paired with the record at the mate position
+fn pair_mates(alns: &[AlnInfo]) -> Vec<(Option<&AlnInfo>, Option<&AlnInfo>)> {
+    let mut used = vec![false; alns.len()];
+    let mut frags = Vec::with_capacity(alns.len());
+    for i in 0..alns.len() {
+        if used[i] {
+            continue;
+        }
+        used[i] = true;
+        let aln = &alns[i];
+        let mut mate = None;
+        if aln.is_paired && !aln.is_mate_unmapped {
+            if let Some(j) = (i + 1..alns.len()).find(|&j| !used[j] && alns[j].is_mate_of(aln)) {
+                used[j] = true;
+                mate = Some(&alns[j]);
+            }
+        }
+        if aln.is_first || !aln.is_paired {
+            frags.push((Some(aln), mate));
+        } else {
+            frags.push((mate, Some(aln)));
+        }
+    }
+    frags
+}
+
+// write the alignments of one read; in paired mode each mate pair
+// becomes a single alignment. Returns the number of alignments merged
+// into others with `dedup_tids`.
+fn write_alignments<W: std::io::Write>(
+    writer: &mut arms::RadWriter<W>,
+    bc: u128,
+    umi: u64,
+    alns: &[AlnInfo],
+    ref_lens: &[u64],
+    opts: &ConvertOpts,
+) -> std::io::Result<u64> {
+    // merged alignments count a mate pair once even in unpaired mode
+    let frags = if opts.paired || opts.dedup_tids {
+        pair_mates(alns)
+    } else {
+        alns.iter().map(|a| (Some(a), None)).collect()
+    };
+
+    // the reference of each fragment with its alignment-level tags
+    let mut entries: Vec<(u32, Vec<arms::TagValue>)> = Vec::with_capacity(frags.len());
+    for (mate1, mate2) in frags {
+        let first = mate1.or(mate2).unwrap();
+        let mut aln_tags = Vec::new();
+        if opts.paired {
+            let mut mate_ori = 0u8;
+            if let Some(m) = mate1 {
+                mate_ori |= MATE1_ALIGNED;
+                if !m.is_reverse {
+                    mate_ori |= MATE1_FW;
+                }
+            }
+            if let Some(m) = mate2 {
+                mate_ori |= MATE2_ALIGNED;
+                if !m.is_reverse {
+                    mate_ori |= MATE2_FW;
+                }
+            }
+            aln_tags.push(arms::TagValue::U8(mate_ori));
+            if opts.mate_positions {
+                // u32::MAX marks a mate that is not aligned
+                for m in [mate1, mate2].iter() {
+                    let pos = m.map_or(u32::MAX, |a| a.pos as u32);
+                    aln_tags.push(arms::TagValue::U32(pos));
+                }
+            }
+        }
+
+        // the leftmost position of the fragment
+        let pos = [mate1, mate2]
+            .iter()
+            .filter_map(|m| m.map(|a| a.pos))
+            .min()
+            .unwrap();
+        if opts.positions {
+            aln_tags.push(arms::TagValue::U32(pos as u32));
+        }
+        if opts.frag_len {
+            aln_tags.push(arms::TagValue::U32(first.insert_size.unsigned_abs() as u32));
+        }
+        if opts.dist_3p {
+            // as in filter_bam, the distance of the alignment start to
+            // the last base of the reference
+            let dist = ref_lens
+                .get(first.tid as usize)
+                .map_or(u32::MAX, |l| (*l as i64 - 1 - pos).max(0) as u32);
+            aln_tags.push(arms::TagValue::U32(dist));
+        }
+        entries.push((first.compressed_tid(), aln_tags));
+    }
+
+    // keep the first alignment to each reference and strand
+    let mut num_merged = 0;
+    if opts.dedup_tids {
+        let mut seen = HashSet::with_capacity(entries.len());
+        let before = entries.len();
+        entries.retain(|(tid, _)| seen.insert(*tid));
+        num_merged = (before - entries.len()) as u64;
+    }
+    if opts.sort_tids {
+        entries.sort_by_key(|(tid, _)| (tid & 0x7fffffff, tid & 0x80000000 == 0));
+    }
+
+    let tids: Vec<u32> = entries.iter().map(|(tid, _)| *tid).collect();
+    let aln_tags: Vec<arms::TagValue> = entries.into_iter().flat_map(|(_, t)| t).collect();
+    writer.write_read_with_tags(bc, umi, &tids, &aln_tags)?;
+    Ok(num_merged)
+}
+
+/// Convert the SAM/BAM file `input_file` to the RAD file `rad_file`, or to
+/// stdout for "-". Reads that cannot be converted are skipped and counted
+/// in the summary; errors that stop the conversion are returned as a
+/// message.
+pub fn bam2rad(
+    input_file: String,
+    rad_file: String,
+    num_threads: u32,
+    opts: &ConvertOpts,
+    log: &slog::Logger,
+) -> Result<ConvertSummary, String> {
+    let start = Instant::now();
+    opts.check(&rad_file)?;
+    // open the input first, so that a missing or unreadable BAM file leaves
+    // no output behind
+    let mut bam = bam::Reader::from_path(&input_file)
+        .map_err(|e| format!("couldn't open {}: {}", input_file, e))?;
+    let bam_bytes = fs::metadata(&input_file)
+        .map_err(|e| format!("couldn't read {}: {}", input_file, e))?
+        .len();
     info! {
         log,
         "Bam file size in bytes {:?}",
         bam_bytes
     };
 
-    if num_threads > 1 {
-        bam.set_threads((num_threads as usize) - 1).unwrap();
-    } else {
-        bam.set_threads(1).unwrap();
-    }
+    bam.set_threads((num_threads as usize).saturating_sub(1).max(1))
+        .map_err(|e| format!("couldn't start the threads reading {}: {}", input_file, e))?;
 
     let hdrv = bam.header().to_owned();
     // let tid_lookup: HashMap<u32, String>  = tid_2_contig(&hdrv);
-    let mut data = Cursor::new(vec![]);
-    // initialize the header (do we need this ?)
-    // let mut hdr = arms::RADHeader::from_bam_header(&hdrv);
-    // number of chunks would be decided when we know
-    // let end_header_pos2 = hdr.get_size();
-    // info!(
-    //     log,
-    //     "end header pos {:?}",
-    //     end_header_pos2,
-    // );
-
-    // file writer
-    // let owriter = Arc::new(Mutex::new(BufWriter::with_capacity(1048576, ofile)));
-    let mut owriter = BufWriter::with_capacity(1048576, ofile);
-    // intermediate buffer
-
-    // write the header
-    {
-        // NOTE: This is hard-coded for unpaired single-cell data
-        // consider if we should generalize this
-        let is_paired = 0u8;
-        data.write_all(&is_paired.to_le_bytes())
-            .expect("couldn't write to output file");
-        let ref_count = hdrv.target_count() as u64;
-        data.write_all(&ref_count.to_le_bytes())
-            .expect("couldn't write to output file");
-        // create longest buffer
-        for t in hdrv.target_names().iter() {
-            let name_size = t.len() as u16;
-            data.write_all(&name_size.to_le_bytes())
-                .expect("coudn't write to output file");
-            data.write_all(&t).expect("coudn't write to output file");
-        }
-        let initial_num_chunks = 0u64;
-        data.write_all(&initial_num_chunks.to_le_bytes())
-            .expect("coudn't write to output file");
+    // initialize the header; the number of chunks is patched
+    // once we know it
+    let mut hdr = arms::RADHeader::from_bam_header(&hdrv);
+    let ref_lens: Vec<u64> = (0..hdrv.target_count())
+        .map(|tid| hdrv.target_len(tid).unwrap_or(0))
+        .collect();
+    if opts.paired {
+        hdr.is_paired = 1;
     }
 
     // test the header
     {
-        info!(log, "ref count: {:?} ", hdrv.target_count(),);
+        info!(log, "ref count: {:?} ", hdr.ref_count,);
     }
 
-    // keep a pointer to header pos
-    let end_header_pos =
-        data.seek(SeekFrom::Current(0)).unwrap() - std::mem::size_of::<u64>() as u64;
-
     // check header position
-    info!(log, "end header pos: {:?}", end_header_pos,);
+    info!(log, "end header pos: {:?}", hdr.num_chunks_offset(),);
 
     // ### start of tags
-    // get the first record for creating flags
-    let mut rec = bam::Record::new();
-    let first_record_exists = bam.read(&mut rec).is_some();
-    if !first_record_exists {
-        crit!(log, "bam file had no records!");
-        std::process::exit(1);
+    // the most common barcode and UMI lengths of the first tagged records
+    // are declared in the file-level tags; reads with other lengths are
+    // skipped. The records read up to then are kept to be converted first.
+    let mut sampled = Vec::new();
+    let mut bc_lens = HashMap::new();
+    let mut umi_lens = HashMap::new();
+    let mut num_tagged = 0;
+    // htslib blocks when reading past the end again with a thread pool
+    let mut at_end = false;
+    while num_tagged < LENGTH_SAMPLE {
+        let mut rec = bam::Record::new();
+        match bam.read(&mut rec) {
+            None => {
+                at_end = true;
+                break;
+            }
+            Some(Ok(())) => (),
+            Some(Err(e)) => return Err(format!("couldn't read {}: {}", input_file, e)),
+        }
+        if let (Some(b), Some(u)) = (
+            first_string_tag(&rec, &opts.cb_tags),
+            first_string_tag(&rec, &opts.umi_tags),
+        ) {
+            *bc_lens.entry(b.len()).or_insert(0u64) += 1;
+            *umi_lens.entry(u.len()).or_insert(0u64) += 1;
+            num_tagged += 1;
+        }
+        sampled.push(rec);
+    }
+    if sampled.is_empty() {
+        return Err("bam file had no records!".to_string());
     }
+    if num_tagged == 0 {
+        return Err(format!(
+            "no record has both a barcode tag ({}) and a UMI tag ({})",
+            opts.cb_tags.join(", "),
+            opts.umi_tags.join(", ")
+        ));
+    }
+    let bclen = most_common_len(&bc_lens) as u16;
+    let umilen = most_common_len(&umi_lens) as u16;
+    // the read-level types are conditional on barcode and umi length
+    let bc_typeid = match bclen {
+        1..=4 => arms::RADType::U8,
+        5..=8 => arms::RADType::U16,
+        9..=16 => arms::RADType::U32,
+        17..=32 => arms::RADType::U64,
+        33..=64 => arms::RADType::U128,
+        l => {
+            return Err(format!("cannot encode barcode of length {} > 64", l));
+        }
+    };
 
-    // Tags we will have
-    // write the tag meta-information section
-    {
-        // file-level
-        let mut num_tags = 2u16;
-        data.write_all(&num_tags.to_le_bytes())
-            .expect("coudn't write to output file");
-        // type-id
-        let mut typeid = 2u8;
-        let mut cb_tag_str = "cblen";
-        let mut umi_tag_str = "ulen";
-
-        // str - type
-        arms::write_str_bin(&cb_tag_str, &arms::RADIntID::U16, &mut data);
-        data.write_all(&typeid.to_le_bytes())
-            .expect("coudn't write to output file");
-
-        // str - type
-        arms::write_str_bin(&umi_tag_str, &arms::RADIntID::U16, &mut data);
-        data.write_all(&typeid.to_le_bytes())
-            .expect("coudn't write to output file");
-
-        // read-level
-        let bc_string_in = str::from_utf8(rec.aux(b"CB").unwrap().string()).unwrap();
-        let umi_string_in = str::from_utf8(rec.aux(b"UR").unwrap().string()).unwrap();
-        let bclen = bc_string_in.len() as u16;
-        let umilen = umi_string_in.len() as u16;
-
-        data.write_all(&num_tags.to_le_bytes())
-            .expect("coudn't write to output file");
-        cb_tag_str = "b";
-        umi_tag_str = "u";
-
-        // type is conditional on barcode and umi length
-        let bc_typeid = match bclen {
-            1..=4 => arms::encode_type_tag(arms::RADType::U8).unwrap(),
-            5..=8 => arms::encode_type_tag(arms::RADType::U16).unwrap(),
-            9..=16 => arms::encode_type_tag(arms::RADType::U32).unwrap(),
-            17..=32 => arms::encode_type_tag(arms::RADType::U64).unwrap(),
-            l => {
-                crit!(log, "cannot encode barcode of length {} > 32", l);
-                std::process::exit(1);
-            }
-        };
+    let umi_typeid = match umilen {
+        1..=4 => arms::RADType::U8,
+        5..=8 => arms::RADType::U16,
+        9..=16 => arms::RADType::U32,
+        17..=32 => arms::RADType::U64,
+        l => {
+            return Err(format!("cannot encode umi of length {} > 32", l));
+        }
+    };
 
-        let umi_typeid = match umilen {
-            1..=4 => arms::encode_type_tag(arms::RADType::U8).unwrap(),
-            5..=8 => arms::encode_type_tag(arms::RADType::U16).unwrap(),
-            9..=16 => arms::encode_type_tag(arms::RADType::U32).unwrap(),
-            17..=32 => arms::encode_type_tag(arms::RADType::U64).unwrap(),
-            l => {
-                crit!(log, "cannot encode umi of length {} > 32", l);
-                std::process::exit(1);
-            }
-        };
+    // alevin-fry reads barcodes as u64, as does unmapped_bc_count.bin
+    if opts.alevin_fry && bclen > 32 {
+        return Err(format!(
+            "alevin-fry cannot read barcodes of {} bases, only of up to 32",
+            bclen
+        ));
+    }
+    let whitelist = match (opts.n_bases, &opts.whitelist) {
+        (NBases::Expand, Some(path)) => Some(
+            read_barcode_list(path, Some(bclen as usize))
+                .map_err(|e| format!("couldn't read the whitelist {}: {}", path.display(), e))?,
+        ),
+        _ => None,
+    };
 
-        //info!(log, "CB LEN : {}, UMI LEN : {}", bclen, umilen);
+    // the output is only created once the input has passed the checks
+    // above, so that they leave no file behind. "-" writes to stdout. The
+    // chunk count can only be patched in regular files; in pipes such as
+    // stdout or a FIFO it stays 0, which readers take as "read up to the
+    // end of the file".
+    let (ofile, mut patch_file): (Box<dyn Write>, Option<File>) = if rad_file == "-" {
+        (Box::new(std::io::stdout()), None)
+    } else {
+        let oname = Path::new(&rad_file);
+        if let Some(parent) = oname.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("couldn't create {}: {}", parent.display(), e))?;
+        }
 
-        arms::write_str_bin(&cb_tag_str, &arms::RADIntID::U16, &mut data);
-        data.write_all(&bc_typeid.to_le_bytes())
-            .expect("coudn't write to output file");
+        if oname.is_file() {
+            std::fs::remove_file(oname)
+                .map_err(|e| format!("couldn't replace {}: {}", rad_file, e))?;
+        }
+        let ofile =
+            File::create(&rad_file).map_err(|e| format!("couldn't create {}: {}", rad_file, e))?;
+        let is_file = ofile.metadata().map(|m| m.is_file()).unwrap_or(false);
+        let patch_file = if is_file {
+            Some(
+                ofile
+                    .try_clone()
+                    .map_err(|e| format!("couldn't reopen {}: {}", rad_file, e))?,
+            )
+        } else {
+            None
+        };
+        (Box::new(ofile), patch_file)
+    };
 
-        arms::write_str_bin(&umi_tag_str, &arms::RADIntID::U16, &mut data);
-        data.write_all(&umi_typeid.to_le_bytes())
-            .expect("coudn't write to output file");
+    let owriter = BufWriter::with_capacity(1048576, ofile);
 
-        // alignment-level
-        num_tags = 1u16;
-        data.write_all(&num_tags.to_le_bytes())
-            .expect("couldn't write to output file");
+    // number of reads per chunk
+    let buf_limit = 10000u32;
 
-        // reference id
-        let refid_str = "compressed_ori_refid";
-        typeid = 3u8;
-        arms::write_str_bin(&refid_str, &arms::RADIntID::U16, &mut data);
-        data.write_all(&typeid.to_le_bytes())
-            .expect("coudn't write to output file");
+    // Tags we will have
+    // write the header and the tag meta-information section
+    let mut writer = {
+        // file-level
+        let mut file_tags = arms::TagSection::new();
+        file_tags.add_tag("cblen", arms::RADType::U16);
+        file_tags.add_tag("ulen", arms::RADType::U16);
+        if opts.compress {
+            file_tags.add_tag(arms::codec::CODEC_TAG, arms::RADType::U8);
+        }
 
-        data.write_all(&bclen.to_le_bytes())
-            .expect("coudn't write to output file");
-        data.write_all(&umilen.to_le_bytes())
-            .expect("coudn't write to output file");
-    }
+        //info!(log, "CB LEN : {}, UMI LEN : {}", bclen, umilen);
+        let mut file_tag_values = vec![arms::TagValue::U16(bclen), arms::TagValue::U16(umilen)];
+        if opts.compress {
+            file_tag_values.push(arms::TagValue::U8(arms::codec::Codec::Zlib.id()));
+        }
 
-    // owriter.lock().unwrap().write_all(data.get_ref()).unwrap();
-    owriter.write_all(data.get_ref()).unwrap();
+        let mut read_tags = arms::TagSection::new();
+        read_tags.add_tag("b", bc_typeid);
+        read_tags.add_tag("u", umi_typeid);
 
-    let mut num_output_chunks = 0u64;
-    let mut local_nrec = 0u32;
-    // let initial_cond : bool = false ;
+        // alignment-level
+        let mut aln_tags = arms::TagSection::new();
+        // reference id
+        aln_tags.add_tag("compressed_ori_refid", arms::RADType::U32);
+        if opts.paired {
+            // which mates are aligned, and their orientation
+            aln_tags.add_tag("mate_ori", arms::RADType::U8);
+            if opts.mate_positions {
+                aln_tags.add_tag("pos1", arms::RADType::U32);
+                aln_tags.add_tag("pos2", arms::RADType::U32);
+            }
+        }
+        if opts.positions {
+            aln_tags.add_tag("pos", arms::RADType::U32);
+        }
+        if opts.frag_len {
+            aln_tags.add_tag("frag_len", arms::RADType::U32);
+        }
+        if opts.dist_3p {
+            aln_tags.add_tag("dist_3p", arms::RADType::U32);
+        }
 
-    // allocate data
-    let buf_limit = 10000u32;
-    data = Cursor::new(Vec::<u8>::with_capacity((buf_limit * 24) as usize));
-    data.write_all(&local_nrec.to_le_bytes()).unwrap();
-    data.write_all(&local_nrec.to_le_bytes()).unwrap();
+        // the records are written with the widths declared here
+        arms::RadWriter::new(
+            owriter,
+            &hdr,
+            &file_tags,
+            &file_tag_values,
+            &read_tags,
+            &aln_tags,
+        )
+        .map_err(|e| format!("couldn't write to {}: {}", rad_file, e))?
+        .chunk_records(buf_limit)
+    };
 
     // calculate number of records
     // let mut total_number_of_records = 0u64;
@@ -247,173 +812,843 @@ pub fn bam2rad(input_file: String, rad_file: String, num_threads: u32, log: &slo
     // history for records that is
     // first seen
     let mut old_qname = String::from("");
-    let mut bc = 0u64;
+    let mut bc = 0u128;
     let mut umi = 0u64;
-    let mut tid_list = Vec::<u32>::new();
-    let mut first_pass = true;
+    let mut alns = Vec::<AlnInfo>::new();
+    let mut summary = ConvertSummary {
+        input_bytes: bam_bytes,
+        cblen: bclen,
+        ulen: umilen,
+        ..Default::default()
+    };
+    // the reads of each barcode that are not mapped, for alevin-fry
+    let mut unmapped_bcs: HashMap<u64, u32> = HashMap::new();
+    let mut last_input_qname = Vec::new();
+    let mut rec = bam::Record::new();
+    let mut sampled = sampled.into_iter();
     //for r in bam.records(){
     loop {
-        if !first_pass {
-            let next_record_exists = bam.read(&mut rec).is_some();
-            if !next_record_exists {
-                break;
+        match sampled.next() {
+            Some(r) => rec = r,
+            None if at_end => break,
+            None => match bam.read(&mut rec) {
+                None => break,
+                Some(Ok(())) => (),
+                Some(Err(e)) => return Err(format!("couldn't read {}: {}", input_file, e)),
+            },
+        }
+
+        // the records of unmapped reads cannot be written even when their
+        // flag is not excluded
+        summary.num_records += 1;
+        let new_input_read = rec.qname() != &last_input_qname[..];
+        if new_input_read {
+            summary.input_reads += 1;
+            last_input_qname = rec.qname().to_vec();
+        }
+        let flags = rec.flags();
+        if flags & opts.exclude_flags != 0
+            || flags & opts.require_flags != opts.require_flags
+            || rec.tid() < 0
+        {
+            summary.excluded_records += 1;
+            if flags & FLAG_UNMAPPED != 0 || rec.tid() < 0 {
+                summary.unmapped_records += 1;
+                if opts.alevin_fry && new_input_read {
+                    let unmapped_bc = first_string_tag(&rec, &opts.cb_tags)
+                        .filter(|b| b.len() == bclen as usize)
+                        .and_then(|b| cb_string_to_u64_with(b, opts.ambiguous).ok());
+                    if let Some(b) = unmapped_bc {
+                        *unmapped_bcs.entry(b).or_insert(0) += 1;
+                    }
+                }
             }
+            continue;
         }
-        first_pass = false;
 
         // let rec = r.unwrap();
-        let is_reverse = rec.is_reverse();
-        let qname_str = str::from_utf8(rec.qname()).unwrap().to_owned();
+        let qname_str = str::from_utf8(rec.qname())
+            .map_err(|_| format!("the read name {:?} is not UTF-8", rec.qname()))?
+            .to_owned();
         let qname = qname_str;
-        let mut tid = rec.tid() as u32;
         if qname == old_qname {
-            if !is_reverse {
-                tid |= 0x80000000;
+            // the alignment list stays empty for reads we skip
+            if !alns.is_empty() {
+                alns.push(AlnInfo::from_record(&rec));
             }
-            tid_list.push(tid);
-            // local_nrec += 1;
             continue;
         }
         // if this is new read and we need to write info
         // for the last read, _unless_ this is the very
         // first read, in which case we shall continue
-        if !tid_list.is_empty() {
-            assert!(!tid_list.is_empty(), "Trying to write empty tid_list");
-            let na = tid_list.len();
-            data.write_all(&(na as u32).to_le_bytes()).unwrap();
-            //bc
-            data.write_all(&(bc as u32).to_le_bytes()).unwrap();
-            //umi
-            data.write_all(&(umi as u32).to_le_bytes()).unwrap();
-            //write tid list
-            for t in tid_list.iter() {
-                data.write_all(&t.to_le_bytes()).unwrap();
-            }
-        }
-
-        // dump if we reach the buf_limit
-        if local_nrec > buf_limit {
-            data.set_position(0);
-            let nbytes = (data.get_ref().len()) as u32;
-            let nrec = local_nrec;
-            // info!(log,"local nrec {:?}-{:?}", local_nrec, tid_list.len());
-            data.write_all(&nbytes.to_le_bytes()).unwrap();
-            data.write_all(&nrec.to_le_bytes()).unwrap();
-            //owriter.lock().unwrap().write_all(data.get_ref()).unwrap();
-            owriter.write_all(data.get_ref()).unwrap();
-            pbar_inner.inc(1);
-            // if num_output_chunks%100 == 0 {
-            //    print!("Processed {} chunks\r", num_output_chunks);
-            // }
-
-            num_output_chunks += 1;
-            local_nrec = 0;
-            data = Cursor::new(Vec::<u8>::with_capacity((buf_limit * 24) as usize));
-            data.write_all(&local_nrec.to_le_bytes()).unwrap();
-            data.write_all(&local_nrec.to_le_bytes()).unwrap();
-
-            // for debugging
-            // if num_output_chunks > expected_bar_length-1 {
-            //     break;
-            // }
+        if !alns.is_empty() {
+            summary.merged_alignments +=
+                write_alignments(&mut writer, bc, umi, &alns, &ref_lens, opts)
+                    .map_err(|e| format!("couldn't write to {}: {}", rad_file, e))?;
+            summary.num_written += 1;
+            pbar_inner.set_position(writer.num_chunks());
         }
         // let tname = tid_lookup.get(&(rec.tid() as u32)).unwrap();
         // let qname_string = str::from_utf8(rec.qname()).unwrap();
 
-        // if this is a new read update the old variables
+        // this is a new read, update the old variables
+        old_qname = qname;
+        alns.clear();
+        summary.num_reads += 1;
         {
-            let bc_string_in = str::from_utf8(rec.aux(b"CB").unwrap().string()).unwrap();
-            let umi_string_in = str::from_utf8(rec.aux(b"UR").unwrap().string()).unwrap();
+            let bc_string_in = match first_string_tag(&rec, &opts.cb_tags) {
+                Some(s) => String::from_utf8_lossy(s),
+                None => {
+                    summary.no_barcode += 1;
+                    continue;
+                }
+            };
+            let umi_string_in = match first_string_tag(&rec, &opts.umi_tags) {
+                Some(s) => String::from_utf8_lossy(s),
+                None => {
+                    summary.no_umi += 1;
+                    continue;
+                }
+            };
 
-            let bc_string = bc_string_in.replacen('N', "A", 1);
-            let umi_string = umi_string_in.replacen('N', "A", 1);
-            if let Some(_pos) = bc_string.find('N') {
+            let mut bc_string = bc_string_in.to_ascii_uppercase();
+            let mut umi_string = umi_string_in.to_ascii_uppercase();
+            if bc_string.len() != bclen as usize || umi_string.len() != umilen as usize {
+                if summary.bad_length < 10 {
+                    warn!(
+                        log,
+                        "skipping read {}: barcode {} or UMI {} does not have the declared length {} or {}",
+                        old_qname,
+                        bc_string,
+                        umi_string,
+                        bclen,
+                        umilen
+                    );
+                }
+                summary.bad_length += 1;
                 continue;
             }
-            if let Some(_pos) = umi_string.find('N') {
-                continue;
+
+            // handle the Ns as `opts.n_bases` says
+            let bc_ns = bc_string.matches('N').count();
+            let umi_ns = umi_string.matches('N').count();
+            let mut expanded = None;
+            if bc_ns + umi_ns > 0 {
+                let keep = match opts.n_bases {
+                    NBases::Drop => false,
+                    NBases::Replace => bc_ns <= 1 && umi_ns <= 1,
+                    NBases::Expand => {
+                        if bc_ns > 0 {
+                            expanded = whitelist
+                                .as_ref()
+                                .and_then(|w| expand_ns(bc_string.as_bytes(), w, opts.ambiguous));
+                        }
+                        umi_ns <= 1 && (bc_ns == 0 || expanded.is_some())
+                    }
+                };
+                if !keep {
+                    summary.dropped_n += 1;
+                    continue;
+                }
+                bc_string = bc_string.replacen('N', "A", 1);
+                umi_string = umi_string.replacen('N', "A", 1);
             }
 
             // convert to u64 following
             // https://github.com/k3yavi/flash/blob/master/src-rs/src/fragments.rs#L162-L176
-            bc = cb_string_to_u64(bc_string.as_bytes()).unwrap();
-            umi = cb_string_to_u64(umi_string.as_bytes()).unwrap();
-            old_qname = qname.clone();
-            tid_list.clear();
-            if !is_reverse {
-                tid |= 0x80000000;
+            let packed = match expanded {
+                Some(b) => Ok(b),
+                None => cb_string_to_u128_with(bc_string.as_bytes(), opts.ambiguous),
             }
-            tid_list.push(tid);
-            local_nrec += 1;
+            .and_then(|b| {
+                cb_string_to_u64_with(umi_string.as_bytes(), opts.ambiguous).map(|u| (b, u))
+            });
+            match packed {
+                Ok((b, u)) => {
+                    bc = b;
+                    umi = u;
+                    if expanded.is_some() {
+                        summary.expanded_n += 1;
+                    } else if bc_ns + umi_ns > 0 {
+                        summary.replaced_n += 1;
+                    }
+                }
+                Err(e) => {
+                    if summary.unpackable < 10 {
+                        warn!(log, "skipping read {}: {}", old_qname, e);
+                    }
+                    summary.unpackable += 1;
+                    continue;
+                }
+            }
+            alns.push(AlnInfo::from_record(&rec));
         }
-        // println!("{:?}\t{:?}\t{:?}\t{:?}\t{:?}\t{:?}",
+        // println!("{:?}\t{:?}\t{:?}\t{:?}\t{:?}",
         //      qname_string,
         //      tname,
         //      bc_string,
         //      umi_string,
         //      num_output_chunks,
-        //      local_nrec,
         // );
-        //na TODO: make is independed of 16-10 length criterion
-        // for debugging
-        // if num_output_chunks > expected_bar_length-1 {
-        //     break;
-        // }
-    }
-
-    if local_nrec > 0 {
-        // println!("In the residual writing part");
-        // first fill the buffer with the last remaining read
-        if !tid_list.is_empty() {
-            assert!(!tid_list.is_empty(), "Trying to write empty tid_list");
-            let na = tid_list.len();
-            data.write_all(&(na as u32).to_le_bytes()).unwrap();
-            //bc
-            data.write_all(&(bc as u32).to_le_bytes()).unwrap();
-            //umi
-            data.write_all(&(umi as u32).to_le_bytes()).unwrap();
-            //write tid list
-            for t in tid_list.iter() {
-                data.write_all(&t.to_le_bytes()).unwrap();
-            }
-        }
+    }
+
+    // write the last remaining read
+    if !alns.is_empty() {
+        summary.merged_alignments += write_alignments(&mut writer, bc, umi, &alns, &ref_lens, opts)
+            .map_err(|e| format!("couldn't write to {}: {}", rad_file, e))?;
+        summary.num_written += 1;
+    }
 
-        data.set_position(0);
-        let nbytes = (data.get_ref().len()) as u32;
-        let nrec = local_nrec;
-        data.write_all(&nbytes.to_le_bytes()).unwrap();
-        data.write_all(&nrec.to_le_bytes()).unwrap();
-        // owriter.lock().unwrap().write_all(data.get_ref()).unwrap();
-        owriter.write_all(data.get_ref()).unwrap();
-        num_output_chunks += 1;
+    // write the last chunk and update the number of chunks
+    let index = writer
+        .finish_stream()
+        .map_err(|e| format!("couldn't write to {}: {}", rad_file, e))?;
+    if let Some(f) = patch_file.as_mut() {
+        f.seek(SeekFrom::Start(hdr.num_chunks_offset()))
+            .and_then(|_| f.write_all(&index.num_chunks().to_le_bytes()))
+            .map_err(|e| format!("couldn't write to {}: {}", rad_file, e))?;
     }
     pbar_inner.finish_with_message("wrote all records.");
 
-    // update chunk size
-    println!();
-    info!(log, "{:?} chunks written", num_output_chunks,);
-
-    // owriter.lock().unwrap().flush();
-    // owriter
-    //     .lock()
-    //     .unwrap()
-    //     .get_ref()
-    //     .seek(SeekFrom::Start(
-    //         end_header_pos,
-    //     ))
-    //     .expect("couldn't seek in output file");
-    // owriter
-    //     .lock()
-    //     .unwrap()
-    //     .write_all(&num_output_chunks.to_le_bytes())
-    //     .expect("couldn't write to output file.");
-    owriter.flush().expect("File buffer could not be flushed");
-    owriter
-        .seek(SeekFrom::Start(end_header_pos))
-        .expect("couldn't seek in output file");
-    owriter
-        .write_all(&num_output_chunks.to_le_bytes())
-        .expect("couldn't write to output file.");
+    eprintln!();
+    summary.num_chunks = index.num_chunks();
+    info!(log, "{:?} chunks written", summary.num_chunks,);
+    info!(
+        log,
+        "wrote {} of {} reads.", summary.num_written, summary.num_reads
+    );
+    for (n, reason) in summary.skipped() {
+        if n > 0 {
+            warn!(log, "skipped {} reads {}.", n, reason);
+        }
+    }
+    if summary.excluded_records > 0 {
+        info!(
+            log,
+            "excluded {} records by their SAM flags or for having no reference.",
+            summary.excluded_records
+        );
+    }
+    if summary.merged_alignments > 0 {
+        info!(
+            log,
+            "merged {} alignments to the same reference and strand as another of their read.",
+            summary.merged_alignments
+        );
+    }
+    if summary.replaced_n > 0 {
+        info!(
+            log,
+            "replaced an N by A in the barcode or UMI of {} reads.", summary.replaced_n
+        );
+    }
+    if summary.expanded_n > 0 {
+        info!(
+            log,
+            "resolved the Ns of the barcode of {} reads on the whitelist.", summary.expanded_n
+        );
+    }
+
+    if opts.index && rad_file == "-" {
+        warn!(log, "no chunk index is written for stdout.");
+    } else if opts.index {
+        let index_file = arms::index::index_path(&rad_file);
+        index
+            .to_path(&index_file)
+            .map_err(|e| format!("couldn't write the chunk index {:?}: {}", index_file, e))?;
+        info!(log, "wrote the chunk index to {:?}.", index_file);
+    }
+
+    if opts.alevin_fry {
+        let dir = Path::new(&rad_file).parent().unwrap();
+        arms::metadata::write_alevin_fry_metadata(dir, &summary, hdr.ref_count, &unmapped_bcs)
+            .map_err(|e| format!("couldn't write the alevin-fry metadata: {}", e))?;
+        if Path::new(&rad_file).file_name() != Some(arms::metadata::RAD_FILE.as_ref()) {
+            warn!(
+                log,
+                "alevin-fry expects the RAD file to be named {}.",
+                arms::metadata::RAD_FILE
+            );
+        }
+        info!(log, "wrote the alevin-fry metadata to {:?}.", dir);
+    }
+
+    if let Some(report_file) = opts.report.as_ref() {
+        summary.rad_bytes = patch_file
+            .as_ref()
+            .and_then(|f| f.metadata().ok())
+            .map(|m| m.len());
+        let report = summary.to_json(&input_file, &rad_file, start.elapsed().as_secs_f64());
+        File::create(report_file)
+            .map_err(serde_json::Error::io)
+            .and_then(|f| serde_json::to_writer_pretty(f, &report))
+            .map_err(|e| format!("couldn't write the report {}: {}", report_file.display(), e))?;
+        info!(log, "wrote the report to {:?}.", report_file);
+    }
 
     info!(log, "finished writing to {:?}.", rad_file);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::ReadRecord;
+    use crate::test_util::TempPath;
+    use crate::RadReader;
+
+    const SAM_HEADER: &str = "@HD\tVN:1.6\n@SQ\tSN:txp1\tLN:1000\n@SQ\tSN:txp2\tLN:500\n";
+
+    // write `records` as a SAM file and convert it, returning the path of the RAD file
+    fn convert_sam(name: &str, records: &[&str], opts: &ConvertOpts) -> TempPath {
+        convert_sam_with_summary(name, records, opts).0
+    }
+
+    fn convert_sam_with_summary(
+        name: &str,
+        records: &[&str],
+        opts: &ConvertOpts,
+    ) -> (TempPath, ConvertSummary) {
+        let (rad, summary) = try_convert_sam(name, records, opts);
+        (rad, summary.unwrap())
+    }
+
+    fn try_convert_sam(
+        name: &str,
+        records: &[&str],
+        opts: &ConvertOpts,
+    ) -> (TempPath, Result<ConvertSummary, String>) {
+        let rad = TempPath::in_dir(name, "map.rad");
+        let sam = rad.with_file_name("in.sam");
+        let mut text = SAM_HEADER.to_string();
+        for r in records.iter() {
+            text.push_str(r);
+            text.push('\n');
+        }
+        std::fs::write(&sam, text).unwrap();
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let summary = bam2rad(
+            sam.to_str().unwrap().to_string(),
+            rad.to_str().unwrap().to_string(),
+            1,
+            opts,
+            &log,
+        );
+        (rad, summary)
+    }
+
+    fn read_all(rad: &Path) -> (RadReader<std::io::BufReader<File>>, Vec<ReadRecord>) {
+        let mut rdr = RadReader::from_path(rad).unwrap();
+        let mut reads = vec![];
+        while let Some(chunk) = rdr.next_chunk().unwrap() {
+            reads.extend(chunk.reads);
+        }
+        (rdr, reads)
+    }
+
+    #[test]
+    fn decodes_packed_barcodes() {
+        for bc in ["ACGT", "TTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTT", "GATTACA", "A"].iter() {
+            let packed = cb_string_to_u64(bc.as_bytes()).unwrap();
+            assert_eq!(u64_to_barcode_string(packed, bc.len()), *bc);
+        }
+    }
+
+    #[test]
+    fn packs_ambiguous_bases_by_policy() {
+        assert_eq!(
+            cb_string_to_u64(b"acgt").unwrap(),
+            cb_string_to_u64(b"ACGT").unwrap()
+        );
+        assert_eq!(
+            cb_string_to_u64_with(b"NRYK", AmbiguousBases::Map).unwrap(),
+            cb_string_to_u64(b"AACG").unwrap()
+        );
+        assert!(cb_string_to_u64_with(b"ACRT", AmbiguousBases::Reject).is_err());
+        assert!(cb_string_to_u64_with(b"AC-T", AmbiguousBases::Map).is_err());
+        assert!(cb_string_to_u64(&[b'A'; 33]).is_err());
+    }
+
+    #[test]
+    fn skips_reads_with_ambiguous_barcodes() {
+        let records = [
+            "r1\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:acgt\tUR:Z:TT",
+            "r2\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACYT\tUR:Z:TT",
+            "r3\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:CCCC\tUR:Z:GA",
+        ];
+        let rad = convert_sam("ambiguous", &records, &ConvertOpts::default());
+        let (_, reads) = read_all(&rad);
+        assert_eq!(reads.len(), 2);
+        assert_eq!(reads[0].bc, cb_string_to_u64(b"ACGT").unwrap() as u128);
+
+        let opts = ConvertOpts {
+            ambiguous: AmbiguousBases::Map,
+            ..Default::default()
+        };
+        let rad = convert_sam("ambiguous-map", &records, &opts);
+        let (_, reads) = read_all(&rad);
+        assert_eq!(reads.len(), 3);
+        assert_eq!(reads[1].bc, cb_string_to_u64(b"ACCT").unwrap() as u128);
+    }
+
+    #[test]
+    fn converts_long_barcodes_and_skips_other_lengths() {
+        let long_bc = "ACGTACGTACGTACGTACGTACGTACGTACGTTTGGCCAA";
+        let r1 = format!(
+            "r1\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:{}\tUR:Z:TT",
+            long_bc
+        );
+        let r3 = format!(
+            "r3\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:{}\tUR:Z:GA",
+            long_bc.replace('A', "C")
+        );
+        let rad = convert_sam(
+            "long",
+            &[
+                &r1,
+                "r2\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                &r3,
+            ],
+            &ConvertOpts::default(),
+        );
+        let (rdr, reads) = read_all(&rad);
+        assert_eq!(rdr.read_tags.tags[0].typeid, arms::RADType::U128);
+        assert_eq!(rdr.file_tag_value("cblen"), Some(&arms::TagValue::U16(40)));
+        assert_eq!(reads.len(), 2);
+        assert_eq!(u128_to_barcode_string(reads[0].bc, 40), long_bc);
+        assert_eq!(
+            u128_to_barcode_string(reads[1].bc, 40),
+            long_bc.replace('A', "C")
+        );
+    }
+
+    #[test]
+    fn uses_the_first_tag_present() {
+        let opts = ConvertOpts {
+            cb_tags: vec!["CB".to_string(), "CR".to_string()],
+            umi_tags: vec!["UB".to_string(), "UR".to_string()],
+            ..Default::default()
+        };
+        let rad = convert_sam(
+            "tags",
+            &[
+                "r1\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCR:Z:AAAA\tCB:Z:ACGT\tUR:Z:TT\tUB:Z:GG",
+                "r2\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCR:Z:CCCC\tUR:Z:GA",
+            ],
+            &opts,
+        );
+        let (_, reads) = read_all(&rad);
+        let tags: Vec<(u128, u64)> = reads.iter().map(|r| (r.bc, r.umi)).collect();
+        assert_eq!(
+            tags,
+            vec![
+                (
+                    cb_string_to_u64(b"ACGT").unwrap() as u128,
+                    cb_string_to_u64(b"GG").unwrap()
+                ),
+                (
+                    cb_string_to_u64(b"CCCC").unwrap() as u128,
+                    cb_string_to_u64(b"GA").unwrap()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_and_counts_unconvertible_reads() {
+        let (rad, summary) = convert_sam_with_summary(
+            "skipped",
+            &[
+                "r1\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII",
+                "r2\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGTA\tUR:Z:TT",
+                "r3\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT",
+                "r4\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                "r5\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:NNGT\tUR:Z:TT",
+                "r6\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACRT\tUR:Z:TT",
+                "r7\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:CCCC\tUR:Z:GA",
+            ],
+            &ConvertOpts::default(),
+        );
+        // the lengths are those of most of the tagged records, not the first
+        let (rdr, reads) = read_all(&rad);
+        assert_eq!(rdr.file_tag_value("cblen"), Some(&arms::TagValue::U16(4)));
+        assert_eq!(reads.len(), 2);
+        assert_eq!(
+            summary,
+            ConvertSummary {
+                input_bytes: summary.input_bytes,
+                rad_bytes: None,
+                num_records: 7,
+                input_reads: 7,
+                num_reads: 7,
+                num_written: 2,
+                num_chunks: 1,
+                no_barcode: 1,
+                no_umi: 1,
+                bad_length: 1,
+                dropped_n: 1,
+                replaced_n: 0,
+                expanded_n: 0,
+                unpackable: 1,
+                excluded_records: 0,
+                unmapped_records: 0,
+                merged_alignments: 0,
+                cblen: 4,
+                ulen: 2,
+            }
+        );
+        assert_eq!(summary.num_skipped(), 5);
+    }
+
+    #[test]
+    fn handles_ns_by_policy() {
+        let records = [
+            "r1\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGN\tUR:Z:TT",
+            "r2\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:CCNN\tUR:Z:TT",
+            "r3\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:GGGG\tUR:Z:TN",
+            "r4\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+        ];
+        let barcodes_of = |rad: &Path| -> Vec<String> {
+            let (_, reads) = read_all(rad);
+            reads
+                .iter()
+                .map(|r| u128_to_barcode_string(r.bc, 4))
+                .collect()
+        };
+
+        let (rad, summary) = convert_sam_with_summary("n-replace", &records, &Default::default());
+        assert_eq!(barcodes_of(&rad), vec!["ACGA", "GGGG", "ACGT"]);
+        assert_eq!((summary.replaced_n, summary.dropped_n), (2, 1));
+
+        let opts = ConvertOpts {
+            n_bases: NBases::Drop,
+            ..Default::default()
+        };
+        let (rad, summary) = convert_sam_with_summary("n-drop", &records, &opts);
+        assert_eq!(barcodes_of(&rad), vec!["ACGT"]);
+        assert_eq!((summary.replaced_n, summary.dropped_n), (0, 3));
+
+        // CCNN matches two barcodes on the whitelist
+        let whitelist = TempPath::new("whitelist.txt");
+        std::fs::write(&whitelist, "ACGT\nCCAA\nCCAT\n").unwrap();
+        let opts = ConvertOpts {
+            n_bases: NBases::Expand,
+            whitelist: Some(whitelist.to_path_buf()),
+            ..Default::default()
+        };
+        let (rad, summary) = convert_sam_with_summary("n-expand", &records, &opts);
+        assert_eq!(barcodes_of(&rad), vec!["ACGT", "GGGG", "ACGT"]);
+        assert_eq!(
+            (summary.expanded_n, summary.replaced_n, summary.dropped_n),
+            (1, 1, 1)
+        );
+    }
+
+    #[test]
+    fn selects_records_by_flag() {
+        let records = [
+            "r1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            "r2\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            "r2\t256\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            "r3\t1024\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:CCCC\tUR:Z:GA",
+        ];
+        let (rad, summary) = convert_sam_with_summary("flags", &records, &Default::default());
+        let (_, reads) = read_all(&rad);
+        assert_eq!(reads.len(), 2);
+        assert_eq!(reads[0].tids, vec![0x80000000, 0x80000001]);
+        assert_eq!((summary.num_reads, summary.excluded_records), (2, 1));
+
+        // -F 0x504: no secondary or duplicate records
+        let opts = ConvertOpts {
+            exclude_flags: 0x504,
+            ..Default::default()
+        };
+        let (rad, summary) = convert_sam_with_summary("flags-f", &records, &opts);
+        let (_, reads) = read_all(&rad);
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].tids, vec![0x80000000]);
+        assert_eq!(summary.excluded_records, 3);
+
+        // -f 0x400 -F 0: only duplicates, and never the unmapped record
+        let opts = ConvertOpts {
+            require_flags: 0x400,
+            exclude_flags: 0,
+            ..Default::default()
+        };
+        let (rad, summary) = convert_sam_with_summary("flags-r", &records, &opts);
+        let (_, reads) = read_all(&rad);
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].tids, vec![0x80000001]);
+        assert_eq!(summary.excluded_records, 3);
+    }
+
+    #[test]
+    fn leaves_no_output_for_a_missing_input() {
+        let rad = TempPath::in_dir("missing-input", "map.rad");
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let summary = bam2rad(
+            rad.with_file_name("nope.bam").to_str().unwrap().to_string(),
+            rad.to_str().unwrap().to_string(),
+            1,
+            &ConvertOpts::default(),
+            &log,
+        );
+        assert!(summary.unwrap_err().contains("nope.bam"));
+        assert!(!rad.exists());
+    }
+
+    #[test]
+    fn leaves_no_output_without_tagged_records() {
+        let (rad, summary) = try_convert_sam(
+            "untagged",
+            &["r1\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT"],
+            &ConvertOpts::default(),
+        );
+        assert!(summary.unwrap_err().contains("no record has both"));
+        assert!(!rad.exists());
+    }
+
+    #[test]
+    fn writes_a_json_report() {
+        let report = TempPath::new("report.json");
+        let opts = ConvertOpts {
+            report: Some(report.to_path_buf()),
+            ..Default::default()
+        };
+        let (rad, summary) = convert_sam_with_summary(
+            "report",
+            &[
+                "r1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                "r2\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                "r2\t256\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                "r3\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:NNGT\tUR:Z:GA",
+                "r4\t0\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tUR:Z:GA",
+            ],
+            &opts,
+        );
+        let js: Value = serde_json::from_str(&std::fs::read_to_string(&report).unwrap()).unwrap();
+        assert_eq!(js["input"]["bytes"], json!(summary.input_bytes));
+        assert_eq!(js["output"]["path"], json!(rad.to_str().unwrap()));
+        assert_eq!(
+            js["output"]["bytes"],
+            json!(std::fs::metadata(&rad).unwrap().len())
+        );
+        assert_eq!(js["output"]["num_chunks"], json!(1));
+        assert_eq!(js["num_records"], json!(5));
+        assert_eq!(js["unmapped_records"], json!(1));
+        assert_eq!(js["num_written"], json!(1));
+        assert_eq!(js["skipped_reads"]["n_bases"], json!(1));
+        assert_eq!(js["skipped_reads"]["no_barcode"], json!(1));
+        assert_eq!((&js["cblen"], &js["ulen"]), (&json!(4), &json!(2)));
+        assert!(js["wall_time_s"].as_f64().unwrap() >= 0.0);
+    }
+
+    #[test]
+    fn converts_unpaired_reads() {
+        let rad = convert_sam(
+            "unpaired",
+            &[
+                "r1\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                "r1\t272\ttxp2\t21\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                "r2\t16\ttxp2\t5\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:CCCC\tUR:Z:GA",
+            ],
+            &ConvertOpts::default(),
+        );
+        let (rdr, reads) = read_all(&rad);
+        assert_eq!(rdr.header.is_paired, 0);
+        assert_eq!(rdr.header.num_chunks, 1);
+        assert_eq!(reads.len(), 2);
+        assert_eq!(reads[0].bc, cb_string_to_u64(b"ACGT").unwrap() as u128);
+        assert_eq!(reads[0].umi, cb_string_to_u64(b"TT").unwrap());
+        assert_eq!(reads[0].tids, vec![0x80000000, 1]);
+        assert_eq!(reads[1].tids, vec![1]);
+    }
+
+    #[test]
+    fn converts_paired_reads() {
+        let opts = ConvertOpts {
+            paired: true,
+            mate_positions: true,
+            ..Default::default()
+        };
+        let rad = convert_sam(
+            "paired",
+            &[
+                "r1\t99\ttxp1\t11\t255\t4M\t=\t101\t94\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                "r1\t147\ttxp1\t101\t255\t4M\t=\t11\t-94\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                "r1\t137\ttxp2\t31\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            ],
+            &opts,
+        );
+        let (rdr, reads) = read_all(&rad);
+        assert_eq!(rdr.header.is_paired, 1);
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].tids, vec![0x80000000, 0x80000001]);
+        assert_eq!(
+            reads[0].aln_tags,
+            vec![
+                arms::TagValue::U8(MATE1_ALIGNED | MATE1_FW | MATE2_ALIGNED),
+                arms::TagValue::U32(10),
+                arms::TagValue::U32(100),
+                arms::TagValue::U8(MATE2_ALIGNED | MATE2_FW),
+                arms::TagValue::U32(u32::MAX),
+                arms::TagValue::U32(30),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_duplicate_alignments() {
+        let records = [
+            "r1\t99\ttxp2\t11\t255\t4M\t=\t101\t94\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            "r1\t147\ttxp2\t101\t255\t4M\t=\t11\t-94\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            "r1\t355\ttxp1\t21\t255\t4M\t=\t121\t94\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            "r1\t403\ttxp1\t121\t255\t4M\t=\t21\t-94\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            "r1\t355\ttxp2\t51\t255\t4M\t=\t151\t94\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            "r1\t403\ttxp2\t151\t255\t4M\t=\t51\t-94\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+        ];
+        let (rad, _) = convert_sam_with_summary("dedup-off", &records, &Default::default());
+        let (_, reads) = read_all(&rad);
+        assert_eq!(reads[0].tids.len(), 6);
+
+        let opts = ConvertOpts {
+            dedup_tids: true,
+            positions: true,
+            ..Default::default()
+        };
+        let (rad, summary) = convert_sam_with_summary("dedup", &records, &opts);
+        let (_, reads) = read_all(&rad);
+        assert_eq!(reads[0].tids, vec![0x80000001, 0x80000000]);
+        assert_eq!(
+            reads[0].aln_tags,
+            vec![arms::TagValue::U32(10), arms::TagValue::U32(20)]
+        );
+        assert_eq!(summary.merged_alignments, 1);
+
+        let opts = ConvertOpts {
+            dedup_tids: true,
+            sort_tids: true,
+            ..opts
+        };
+        let (rad, _) = convert_sam_with_summary("dedup-sort", &records, &opts);
+        let (_, reads) = read_all(&rad);
+        assert_eq!(reads[0].tids, vec![0x80000000, 0x80000001]);
+        assert_eq!(
+            reads[0].aln_tags,
+            vec![arms::TagValue::U32(20), arms::TagValue::U32(10)]
+        );
+    }
+
+    #[test]
+    fn writes_alevin_fry_metadata() {
+        let records = [
+            "r1\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            "r2\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:GA",
+            "r3\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:CCCC\tUR:Z:GA",
+            "r3\t256\ttxp2\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:CCCC\tUR:Z:GA",
+        ];
+        let opts = ConvertOpts {
+            alevin_fry: true,
+            ..Default::default()
+        };
+        let (rad, summary) = convert_sam_with_summary("alevin-fry", &records, &opts);
+        assert_eq!((summary.input_reads, summary.num_written), (3, 1));
+
+        let dir = rad.parent().unwrap();
+        let map_info: Value =
+            serde_json::from_reader(File::open(dir.join(crate::metadata::MAP_INFO_FILE)).unwrap())
+                .unwrap();
+        assert_eq!(map_info["num_reads"], json!(3));
+        assert_eq!(map_info["num_mapped"], json!(1));
+        assert!(dir.join(crate::metadata::META_INFO_FILE).exists());
+
+        let acgt = cb_string_to_u64_with(b"ACGT", AmbiguousBases::Reject).unwrap();
+        let unmapped = crate::metadata::unmapped_bc_counts_of(dir).unwrap();
+        assert_eq!(unmapped, [(acgt, 2)].iter().cloned().collect());
+    }
+
+    #[test]
+    fn rejects_options_alevin_fry_cannot_read() {
+        let alevin_fry = ConvertOpts {
+            alevin_fry: true,
+            ..Default::default()
+        };
+        let rejected = [
+            ConvertOpts {
+                compress: true,
+                ..alevin_fry.clone()
+            },
+            ConvertOpts {
+                paired: true,
+                ..alevin_fry.clone()
+            },
+            ConvertOpts {
+                positions: true,
+                ..alevin_fry.clone()
+            },
+            ConvertOpts {
+                frag_len: true,
+                ..alevin_fry.clone()
+            },
+            ConvertOpts {
+                dist_3p: true,
+                ..alevin_fry.clone()
+            },
+        ];
+        let records = ["r1\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT"];
+        for opts in rejected.iter() {
+            let (rad, summary) = try_convert_sam("alevin-fry-options", &records, opts);
+            assert!(summary.unwrap_err().contains("alevin-fry cannot read"));
+            assert!(!rad.exists());
+        }
+        assert!(alevin_fry.check("-").is_err());
+        assert!(alevin_fry.check("map.rad").is_ok());
+    }
+
+    #[test]
+    fn rejects_long_barcodes_for_alevin_fry() {
+        let opts = ConvertOpts {
+            alevin_fry: true,
+            ..Default::default()
+        };
+        let r1 = format!(
+            "r1\t0\ttxp1\t11\t255\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:{}\tUR:Z:TT",
+            "ACGT".repeat(10)
+        );
+        let (_, summary) = try_convert_sam("alevin-fry-long", &[&r1], &opts);
+        assert!(summary.unwrap_err().contains("40 bases"));
+    }
+
+    #[test]
+    fn writes_position_tags() {
+        let opts = ConvertOpts {
+            positions: true,
+            frag_len: true,
+            dist_3p: true,
+            ..Default::default()
+        };
+        let rad = convert_sam(
+            "positions",
+            &[
+                "r1\t0\ttxp1\t11\t255\t4M\t*\t0\t250\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+                "r1\t256\ttxp2\t401\t255\t4M\t*\t0\t-120\tACGT\tIIII\tCB:Z:ACGT\tUR:Z:TT",
+            ],
+            &opts,
+        );
+        let (rdr, reads) = read_all(&rad);
+        let names: Vec<&str> = rdr.aln_tags.tags.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["compressed_ori_refid", "pos", "frag_len", "dist_3p"]);
+        assert_eq!(
+            reads[0].aln_tags,
+            vec![
+                arms::TagValue::U32(10),
+                arms::TagValue::U32(250),
+                arms::TagValue::U32(989),
+                arms::TagValue::U32(400),
+                arms::TagValue::U32(120),
+                arms::TagValue::U32(99),
+            ]
+        );
+    }
 }
@@ -0,0 +1,226 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{RADIntID, RadReader};
+
+// the first bytes of an index file, followed by the format version
+const INDEX_MAGIC: &[u8; 4] = b"RADI";
+const INDEX_VERSION: u32 = 2;
+
+/// Where a chunk of a RAD file is and which barcodes it holds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChunkIndexEntry {
+    /// the byte offset of the chunk in the RAD file
+    pub offset: u64,
+    pub nbytes: u32,
+    pub nrec: u32,
+    pub min_bc: u128,
+    pub max_bc: u128,
+}
+
+impl ChunkIndexEntry {
+    // offset, nbytes, nrec, min_bc, max_bc
+    const SIZE: usize = 8 + 4 + 4 + 16 + 16;
+
+    pub fn write_to<W: Write>(&self, owriter: &mut W) -> std::io::Result<()> {
+        owriter.write_all(&self.offset.to_le_bytes())?;
+        owriter.write_all(&self.nbytes.to_le_bytes())?;
+        owriter.write_all(&self.nrec.to_le_bytes())?;
+        owriter.write_all(&self.min_bc.to_le_bytes())?;
+        owriter.write_all(&self.max_bc.to_le_bytes())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<ChunkIndexEntry> {
+        Ok(ChunkIndexEntry {
+            offset: RADIntID::U64.read_from(reader)?,
+            nbytes: RADIntID::U32.read_from(reader)? as u32,
+            nrec: RADIntID::U32.read_from(reader)? as u32,
+            min_bc: RADIntID::U128.read_u128_from(reader)?,
+            max_bc: RADIntID::U128.read_u128_from(reader)?,
+        })
+    }
+
+    /// Whether barcode `bc` may occur in the chunk.
+    pub fn may_contain(&self, bc: u128) -> bool {
+        self.min_bc <= bc && bc <= self.max_bc
+    }
+}
+
+/// An index of the chunks of a RAD file, kept in a sidecar file next to it
+/// (see `index_path`), so that tools can seek to a chunk without scanning
+/// the chunks before it.
+///
+/// The index file starts with the magic `RADI`, a u32 format version and
+/// the u64 number of chunks, followed by one entry per chunk, all little
+/// endian.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RadIndex {
+    pub chunks: Vec<ChunkIndexEntry>,
+}
+
+/// The path of the index of the RAD file at `rad_file`, i.e. the path with
+/// `.idx` appended.
+pub fn index_path<P: AsRef<Path>>(rad_file: P) -> PathBuf {
+    let mut path = rad_file.as_ref().as_os_str().to_owned();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+impl RadIndex {
+    pub fn num_chunks(&self) -> u64 {
+        self.chunks.len() as u64
+    }
+
+    /// Index the chunks of `rdr`, reading all of them.
+    pub fn build<R: Read>(mut rdr: RadReader<R>) -> std::io::Result<RadIndex> {
+        let mut index = RadIndex::default();
+        loop {
+            let offset = rdr.position();
+            let chunk = match rdr.next_chunk()? {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            index.chunks.push(ChunkIndexEntry {
+                offset,
+                nbytes: chunk.nbytes,
+                nrec: chunk.nrec,
+                min_bc: chunk.reads.iter().map(|r| r.bc).min().unwrap_or(u128::MAX),
+                max_bc: chunk.reads.iter().map(|r| r.bc).max().unwrap_or(0),
+            });
+        }
+        Ok(index)
+    }
+
+    /// The indices of the chunks that may hold reads of barcode `bc`.
+    pub fn chunks_with_barcode(&self, bc: u128) -> impl Iterator<Item = usize> + '_ {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(move |(_, c)| c.may_contain(bc))
+            .map(|(i, _)| i)
+    }
+
+    pub fn write_to<W: Write>(&self, owriter: &mut W) -> std::io::Result<()> {
+        owriter.write_all(INDEX_MAGIC)?;
+        owriter.write_all(&INDEX_VERSION.to_le_bytes())?;
+        owriter.write_all(&self.num_chunks().to_le_bytes())?;
+        for c in self.chunks.iter() {
+            c.write_to(owriter)?;
+        }
+        owriter.flush()
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<RadIndex> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a RAD chunk index"));
+        }
+        let version = RADIntID::U32.read_from(reader)? as u32;
+        if version != INDEX_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported RAD chunk index version {}", version),
+            ));
+        }
+        let num_chunks = RADIntID::U64.read_from(reader)?;
+        let mut chunks = Vec::with_capacity(num_chunks.min(1 << 20) as usize);
+        for _ in 0..num_chunks {
+            chunks.push(ChunkIndexEntry::read_from(reader)?);
+        }
+        Ok(RadIndex { chunks })
+    }
+
+    pub fn to_path<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut owriter = BufWriter::new(File::create(path)?);
+        self.write_to(&mut owriter)
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<RadIndex> {
+        let mut reader = BufReader::new(File::open(path)?);
+        RadIndex::read_from(&mut reader)
+    }
+
+    /// The size of the index file.
+    pub fn get_size(&self) -> usize {
+        INDEX_MAGIC.len() + 4 + 8 + self.chunks.len() * ChunkIndexEntry::SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestRad;
+    use crate::{RADType, RadWriter};
+    use std::io::Cursor;
+
+    fn example_rad() -> (Vec<u8>, RadIndex) {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .lengths(RADType::U32, 16, RADType::U16, 8)
+            .writer(&mut out)
+            .chunk_records(3);
+        for i in 0..8u64 {
+            writer.write_read(10 - i as u128, i, &[0x80000000, 1]).unwrap();
+        }
+        let index = writer.finish_with_index().unwrap();
+        (out.into_inner(), index)
+    }
+
+    #[test]
+    fn writer_and_scan_agree() {
+        let (bytes, index) = example_rad();
+        assert_eq!(index.num_chunks(), 3);
+        assert_eq!(
+            index.chunks[1],
+            ChunkIndexEntry {
+                offset: index.chunks[0].offset + index.chunks[0].nbytes as u64,
+                nbytes: index.chunks[0].nbytes,
+                nrec: 3,
+                min_bc: 5,
+                max_bc: 7,
+            }
+        );
+        let rdr = RadReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(RadIndex::build(rdr).unwrap(), index);
+
+        // copying the chunks as they are yields the same index
+        let mut rdr = RadReader::new(Cursor::new(bytes)).unwrap();
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = RadWriter::with_header_of(&mut out, &rdr).unwrap();
+        while let Some(raw) = rdr.next_raw_chunk().unwrap() {
+            writer.write_raw_chunk(&raw).unwrap();
+        }
+        assert_eq!(writer.finish_with_index().unwrap(), index);
+        assert_eq!(index.chunks_with_barcode(9).collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let (_, index) = example_rad();
+        let mut data = Cursor::new(Vec::new());
+        index.write_to(&mut data).unwrap();
+        assert_eq!(data.get_ref().len(), index.get_size());
+        data.set_position(0);
+        assert_eq!(RadIndex::read_from(&mut data).unwrap(), index);
+
+        let mut bad = data.into_inner();
+        bad[0] = b'X';
+        assert!(RadIndex::read_from(&mut Cursor::new(bad)).is_err());
+    }
+
+    #[test]
+    fn seeks_to_chunk() {
+        let (bytes, index) = example_rad();
+        let mut rdr = RadReader::new(Cursor::new(bytes)).unwrap();
+        rdr.seek_to_chunk(&index, 2).unwrap();
+        let chunk = rdr.next_chunk().unwrap().unwrap();
+        assert_eq!(
+            chunk.reads.iter().map(|r| r.bc).collect::<Vec<_>>(),
+            vec![4, 3]
+        );
+        assert!(rdr.next_chunk().unwrap().is_none());
+        assert_eq!(index_path("out/map.rad"), PathBuf::from("out/map.rad.idx"));
+    }
+}
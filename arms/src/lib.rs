@@ -1,24 +1,93 @@
 use num::cast::AsPrimitive;
-use std::io::{Write, Cursor};
+use rust_htslib::bam::HeaderView;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
 
 
 pub mod filter;
 pub mod convert;
 pub mod config;
+pub mod reader;
+pub mod writer;
+pub mod view;
+pub mod validate;
+pub mod stats;
+pub mod merge;
+pub mod subset;
+pub mod rad2bam;
+pub mod index;
+pub mod collate;
+pub mod codec;
+pub mod metadata;
+
+pub use reader::RadReader;
+pub use writer::RadWriter;
+
+#[cfg(test)]
+mod test_util;
 
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::io::Cursor;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn header_round_trip() {
+        let hdr = RADHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["txp1".to_string(), "txp22".to_string()],
+            num_chunks: 3,
+        };
+        let mut data = Cursor::new(vec![]);
+        hdr.write_to(&mut data).unwrap();
+        assert_eq!(data.get_ref().len(), hdr.get_size());
+        assert_eq!(
+            &data.get_ref()[hdr.num_chunks_offset() as usize..],
+            &3u64.to_le_bytes()
+        );
+
+        data.set_position(0);
+        assert_eq!(RADHeader::read_from(&mut data).unwrap(), hdr);
+
+        // a reference count far beyond the names that follow
+        let mut bytes = data.into_inner();
+        bytes[1..9].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(RADHeader::read_from(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn tag_section_round_trip() {
+        let mut tags = TagSection::new();
+        tags.add_tag("cblen", RADType::U16);
+        tags.add_tag("score", RADType::F32);
+        let values = [TagValue::U16(16), TagValue::F32(0.5)];
+
+        let mut data = Cursor::new(vec![]);
+        tags.write_to(&mut data).unwrap();
+        tags.write_values(&values, &mut data).unwrap();
+        assert!(tags
+            .write_values(&[TagValue::U32(16), TagValue::F32(0.5)], &mut data)
+            .is_err());
+
+        data.set_position(0);
+        let read_tags = TagSection::read_from(&mut data).unwrap();
+        assert_eq!(read_tags, tags);
+        assert_eq!(read_tags.position("score"), Some(1));
+        assert_eq!(read_tags.read_values(&mut data).unwrap(), values);
+    }
 }
 
 
 
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum RADType {
     BOOL,
     U8,
@@ -27,6 +96,9 @@ pub enum RADType {
     U64,
     F32,
     F64,
+    /// 128-bit integers, for barcodes of more than 32 bases; this type is
+    /// not part of alevin-fry's RAD format
+    U128,
 }
 
 
@@ -40,16 +112,343 @@ pub fn encode_type_tag(type_tag: RADType) -> Option<u8> {
         RADType::U64 => Some(4),
         RADType::F32 => Some(5),
         RADType::F64 => Some(6),
+        RADType::U128 => Some(9),
         //_ => None,
     }
 }
 
-#[derive(Copy, Clone)]
+pub fn decode_type_tag(type_id: u8) -> Option<RADType> {
+    match type_id {
+        0 => Some(RADType::BOOL),
+        1 => Some(RADType::U8),
+        2 => Some(RADType::U16),
+        3 => Some(RADType::U32),
+        4 => Some(RADType::U64),
+        5 => Some(RADType::F32),
+        6 => Some(RADType::F64),
+        9 => Some(RADType::U128),
+        _ => None,
+    }
+}
+
+impl RADType {
+    pub fn bytes_for_type(&self) -> usize {
+        match self {
+            Self::BOOL => std::mem::size_of::<u8>(),
+            Self::U8 => std::mem::size_of::<u8>(),
+            Self::U16 => std::mem::size_of::<u16>(),
+            Self::U32 => std::mem::size_of::<u32>(),
+            Self::U64 => std::mem::size_of::<u64>(),
+            Self::F32 => std::mem::size_of::<f32>(),
+            Self::F64 => std::mem::size_of::<f64>(),
+            Self::U128 => std::mem::size_of::<u128>(),
+        }
+    }
+
+    /// The integer id type used to store values of this type, if it is an
+    /// integer type (booleans are stored as a single byte).
+    pub fn int_id(&self) -> Option<RADIntID> {
+        match self {
+            Self::BOOL | Self::U8 => Some(RADIntID::U8),
+            Self::U16 => Some(RADIntID::U16),
+            Self::U32 => Some(RADIntID::U32),
+            Self::U64 => Some(RADIntID::U64),
+            Self::U128 => Some(RADIntID::U128),
+            Self::F32 | Self::F64 => None,
+        }
+    }
+}
+
+/// The RAD file header: whether the data are paired-end, the reference
+/// names and the number of chunks that follow the tag sections.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RADHeader {
+    pub is_paired: u8,
+    pub ref_count: u64,
+    pub ref_names: Vec<String>,
+    pub num_chunks: u64,
+}
+
+impl RADHeader {
+    /// Build a header with the references of a BAM file; the number
+    /// of chunks starts out at 0 and is set once the chunks are written.
+    pub fn from_bam_header(header: &HeaderView) -> RADHeader {
+        let ref_names: Vec<String> = header
+            .target_names()
+            .iter()
+            .map(|t| String::from_utf8_lossy(t).into_owned())
+            .collect();
+        RADHeader {
+            is_paired: 0,
+            ref_count: ref_names.len() as u64,
+            ref_names,
+            num_chunks: 0,
+        }
+    }
+
+    /// The number of bytes the serialized header occupies.
+    pub fn get_size(&self) -> usize {
+        let mut tot_size = std::mem::size_of::<u8>() + 2 * std::mem::size_of::<u64>();
+        for t in self.ref_names.iter() {
+            tot_size += std::mem::size_of::<u16>() + t.len();
+        }
+        tot_size
+    }
+
+    /// The byte offset of `num_chunks` from the start of the header,
+    /// needed to patch the count once all chunks are written.
+    pub fn num_chunks_offset(&self) -> u64 {
+        (self.get_size() - std::mem::size_of::<u64>()) as u64
+    }
+
+    pub fn write_to<U: Write>(&self, owriter: &mut U) -> std::io::Result<()> {
+        owriter.write_all(&self.is_paired.to_le_bytes())?;
+        owriter.write_all(&self.ref_count.to_le_bytes())?;
+        for t in self.ref_names.iter() {
+            RADIntID::U16.write_to(t.len(), owriter)?;
+            owriter.write_all(t.as_bytes())?;
+        }
+        owriter.write_all(&self.num_chunks.to_le_bytes())
+    }
+
+    pub fn read_from<U: Read>(reader: &mut U) -> std::io::Result<RADHeader> {
+        let is_paired = RADIntID::U8.read_from(reader)? as u8;
+        let ref_count = RADIntID::U64.read_from(reader)?;
+        // a corrupt ref_count must not size the vector; reading the names
+        // fails at the end of the input instead
+        let mut ref_names = Vec::new();
+        for _ in 0..ref_count {
+            ref_names.push(read_str_bin(&RADIntID::U16, reader)?);
+        }
+        let num_chunks = RADIntID::U64.read_from(reader)?;
+        Ok(RADHeader {
+            is_paired,
+            ref_count,
+            ref_names,
+            num_chunks,
+        })
+    }
+}
+
+/// The description of a single tag: its name and the type of its values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagDesc {
+    pub name: String,
+    pub typeid: RADType,
+}
+
+impl TagDesc {
+    pub fn new(name: &str, typeid: RADType) -> TagDesc {
+        TagDesc {
+            name: name.to_string(),
+            typeid,
+        }
+    }
+
+    /// The number of bytes the serialized description occupies.
+    pub fn get_size(&self) -> usize {
+        std::mem::size_of::<u16>() + self.name.len() + std::mem::size_of::<u8>()
+    }
+
+    pub fn write_to<U: Write>(&self, owriter: &mut U) -> std::io::Result<()> {
+        RADIntID::U16.write_to(self.name.len(), owriter)?;
+        owriter.write_all(self.name.as_bytes())?;
+        owriter.write_all(&encode_type_tag(self.typeid).unwrap().to_le_bytes())
+    }
+
+    pub fn read_from<U: Read>(reader: &mut U) -> std::io::Result<TagDesc> {
+        let name = read_str_bin(&RADIntID::U16, reader)?;
+        let type_id = RADIntID::U8.read_from(reader)? as u8;
+        match decode_type_tag(type_id) {
+            Some(typeid) => Ok(TagDesc { name, typeid }),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown type id {} for tag {}", type_id, name),
+            )),
+        }
+    }
+}
+
+/// One of the file-, read- or alignment-level tag sections. The values
+/// of the tags in a section are stored in the order of their descriptions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TagSection {
+    pub tags: Vec<TagDesc>,
+}
+
+impl TagSection {
+    pub fn new() -> TagSection {
+        TagSection { tags: Vec::new() }
+    }
+
+    pub fn add_tag(&mut self, name: &str, typeid: RADType) {
+        self.tags.push(TagDesc::new(name, typeid));
+    }
+
+    /// The index of the tag `name` within this section.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.tags.iter().position(|t| t.name == name)
+    }
+
+    /// The number of bytes the serialized tag descriptions occupy.
+    pub fn get_size(&self) -> usize {
+        std::mem::size_of::<u16>() + self.tags.iter().map(|t| t.get_size()).sum::<usize>()
+    }
+
+    /// The number of bytes one value of each tag occupies.
+    pub fn values_size(&self) -> usize {
+        self.tags.iter().map(|t| t.typeid.bytes_for_type()).sum()
+    }
+
+    pub fn write_to<U: Write>(&self, owriter: &mut U) -> std::io::Result<()> {
+        RADIntID::U16.write_to(self.tags.len(), owriter)?;
+        for t in self.tags.iter() {
+            t.write_to(owriter)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<U: Read>(reader: &mut U) -> std::io::Result<TagSection> {
+        let num_tags = RADIntID::U16.read_from(reader)?;
+        let mut tags = Vec::with_capacity(num_tags as usize);
+        for _ in 0..num_tags {
+            tags.push(TagDesc::read_from(reader)?);
+        }
+        Ok(TagSection { tags })
+    }
+
+    /// Read one value for each tag of this section.
+    pub fn read_values<U: Read>(&self, reader: &mut U) -> std::io::Result<Vec<TagValue>> {
+        self.tags
+            .iter()
+            .map(|t| TagValue::read_from(t.typeid, reader))
+            .collect()
+    }
+
+    /// Write `values`, which must match the tags of this section in
+    /// number and type.
+    pub fn write_values<U: Write>(
+        &self,
+        values: &[TagValue],
+        owriter: &mut U,
+    ) -> std::io::Result<()> {
+        if values.len() != self.tags.len()
+            || self
+                .tags
+                .iter()
+                .zip(values.iter())
+                .any(|(t, v)| t.typeid != v.typeid())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "tag values do not match the tag descriptions",
+            ));
+        }
+        for v in values.iter() {
+            v.write_to(owriter)?;
+        }
+        Ok(())
+    }
+}
+
+/// A single tag value.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TagValue {
+    BOOL(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    U128(u128),
+}
+
+impl std::fmt::Display for TagValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BOOL(v) => write!(f, "{}", v),
+            Self::U8(v) => write!(f, "{}", v),
+            Self::U16(v) => write!(f, "{}", v),
+            Self::U32(v) => write!(f, "{}", v),
+            Self::U64(v) => write!(f, "{}", v),
+            Self::F32(v) => write!(f, "{}", v),
+            Self::F64(v) => write!(f, "{}", v),
+            Self::U128(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl TagValue {
+    pub fn typeid(&self) -> RADType {
+        match self {
+            Self::BOOL(_) => RADType::BOOL,
+            Self::U8(_) => RADType::U8,
+            Self::U16(_) => RADType::U16,
+            Self::U32(_) => RADType::U32,
+            Self::U64(_) => RADType::U64,
+            Self::F32(_) => RADType::F32,
+            Self::F64(_) => RADType::F64,
+            Self::U128(_) => RADType::U128,
+        }
+    }
+
+    /// The value as a `u64`, if it is an integer (or boolean) that fits.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::BOOL(v) => Some(*v as u64),
+            Self::U8(v) => Some(*v as u64),
+            Self::U16(v) => Some(*v as u64),
+            Self::U32(v) => Some(*v as u64),
+            Self::U64(v) => Some(*v),
+            Self::U128(v) => u64::try_from(*v).ok(),
+            Self::F32(_) | Self::F64(_) => None,
+        }
+    }
+
+    pub fn write_to<U: Write>(&self, owriter: &mut U) -> std::io::Result<()> {
+        match self {
+            Self::BOOL(v) => owriter.write_all(&(*v as u8).to_le_bytes()),
+            Self::U8(v) => owriter.write_all(&v.to_le_bytes()),
+            Self::U16(v) => owriter.write_all(&v.to_le_bytes()),
+            Self::U32(v) => owriter.write_all(&v.to_le_bytes()),
+            Self::U64(v) => owriter.write_all(&v.to_le_bytes()),
+            Self::F32(v) => owriter.write_all(&v.to_le_bytes()),
+            Self::F64(v) => owriter.write_all(&v.to_le_bytes()),
+            Self::U128(v) => owriter.write_all(&v.to_le_bytes()),
+        }
+    }
+
+    pub fn read_from<U: Read>(typeid: RADType, reader: &mut U) -> std::io::Result<TagValue> {
+        let v = match typeid {
+            RADType::BOOL => Self::BOOL(RADIntID::U8.read_from(reader)? != 0),
+            RADType::U8 => Self::U8(RADIntID::U8.read_from(reader)? as u8),
+            RADType::U16 => Self::U16(RADIntID::U16.read_from(reader)? as u16),
+            RADType::U32 => Self::U32(RADIntID::U32.read_from(reader)? as u32),
+            RADType::U64 => Self::U64(RADIntID::U64.read_from(reader)?),
+            RADType::U128 => Self::U128(RADIntID::U128.read_u128_from(reader)?),
+            RADType::F32 => {
+                let mut buf = [0u8; 4];
+                reader.read_exact(&mut buf)?;
+                Self::F32(f32::from_le_bytes(buf))
+            }
+            RADType::F64 => {
+                let mut buf = [0u8; 8];
+                reader.read_exact(&mut buf)?;
+                Self::F64(f64::from_le_bytes(buf))
+            }
+        };
+        Ok(v)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum RADIntID {
     U8,
     U16,
     U32,
     U64,
+    U128,
 }
 
 impl RADIntID {
@@ -59,6 +458,7 @@ impl RADIntID {
             Self::U16 => std::mem::size_of::<u16>(),
             Self::U32 => std::mem::size_of::<u32>(),
             Self::U64 => std::mem::size_of::<u64>(),
+            Self::U128 => std::mem::size_of::<u128>(),
         }
     }
 
@@ -90,6 +490,59 @@ impl RADIntID {
                 let vo: u64 = v.as_();
                 owriter.write_all(&vo.to_le_bytes())
             }
+            Self::U128 => {
+                let vo: u128 = v.as_();
+                owriter.write_all(&vo.to_le_bytes())
+            }
+        }
+    }
+
+    /// Read a single value of the width given by the current enum
+    /// variant from `reader`, widened to a `u64`. A 128-bit value that
+    /// does not fit is an error; see `read_u128_from`.
+    pub fn read_from<U: Read>(&self, reader: &mut U) -> std::io::Result<u64> {
+        match self {
+            Self::U128 => {
+                let v = self.read_u128_from(reader)?;
+                u64::try_from(v).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{} does not fit into 64 bits", v),
+                    )
+                })
+            }
+            Self::U8 => {
+                let mut buf = [0u8; 1];
+                reader.read_exact(&mut buf)?;
+                Ok(u8::from_le_bytes(buf) as u64)
+            }
+            Self::U16 => {
+                let mut buf = [0u8; 2];
+                reader.read_exact(&mut buf)?;
+                Ok(u16::from_le_bytes(buf) as u64)
+            }
+            Self::U32 => {
+                let mut buf = [0u8; 4];
+                reader.read_exact(&mut buf)?;
+                Ok(u32::from_le_bytes(buf) as u64)
+            }
+            Self::U64 => {
+                let mut buf = [0u8; 8];
+                reader.read_exact(&mut buf)?;
+                Ok(u64::from_le_bytes(buf))
+            }
+        }
+    }
+
+    /// Like `read_from`, but widened to a `u128`.
+    pub fn read_u128_from<U: Read>(&self, reader: &mut U) -> std::io::Result<u128> {
+        match self {
+            Self::U128 => {
+                let mut buf = [0u8; 16];
+                reader.read_exact(&mut buf)?;
+                Ok(u128::from_le_bytes(buf))
+            }
+            _ => self.read_from(reader).map(|v| v as u128),
         }
     }
 }
@@ -102,6 +555,7 @@ pub trait PrimitiveInteger:
     + AsPrimitive<u16>
     + AsPrimitive<u32>
     + AsPrimitive<u64>
+    + AsPrimitive<u128>
     + AsPrimitive<usize>
     + AsPrimitive<i8>
     + AsPrimitive<i16>
@@ -116,6 +570,7 @@ impl<
             + AsPrimitive<u16>
             + AsPrimitive<u32>
             + AsPrimitive<u64>
+            + AsPrimitive<u128>
             + AsPrimitive<usize>
             + AsPrimitive<i8>
             + AsPrimitive<i16>
@@ -127,7 +582,7 @@ impl<
 }
 
 
-pub fn write_str_bin(v: &str, type_id: &RADIntID, owriter: &mut Cursor<Vec<u8>>) {
+pub fn write_str_bin<U: Write>(v: &str, type_id: &RADIntID, owriter: &mut U) {
     match type_id {
         RADIntID::U8 => {
             owriter
@@ -149,8 +604,23 @@ pub fn write_str_bin(v: &str, type_id: &RADIntID, owriter: &mut Cursor<Vec<u8>>)
                 .write_all(&(v.len() as u64).to_le_bytes())
                 .expect("coudn't write to output file");
         }
+        RADIntID::U128 => {
+            owriter
+                .write_all(&(v.len() as u128).to_le_bytes())
+                .expect("coudn't write to output file");
+        }
     }
     owriter
         .write_all(v.as_bytes())
         .expect("coudn't write to output file");
-}
\ No newline at end of file
+}
+
+/// Read a string written by `write_str_bin`, i.e. a length of width
+/// `type_id` followed by the string bytes.
+pub fn read_str_bin<U: Read>(type_id: &RADIntID, reader: &mut U) -> std::io::Result<String> {
+    let len = type_id.read_from(reader)? as usize;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
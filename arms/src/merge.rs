@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Seek, Write};
+use std::path::Path;
+
+use crate::codec::CODEC_TAG;
+use crate::reader::{is_fw, ref_id, FW_MASK};
+use crate::{RADHeader, RadReader, RadWriter};
+
+fn incompatible(path: &Path, msg: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("{} cannot be merged: {}", path.display(), msg),
+    )
+}
+
+/// Concatenate the chunks of several RAD files into `owriter`, returning
+/// the number of chunks written.
+///
+/// The inputs must agree on whether they are paired, on their tag
+/// descriptions and on the `cblen`, `ulen` and `codec` file-level tags; the other
+/// file-level tag values are taken from the first input. With `remap`,
+/// inputs whose reference lists differ from that of the first input are
+/// accepted: references missing from the first list are appended to it,
+/// and the reference ids of the reads are rewritten to match.
+pub fn merge_rad<P: AsRef<Path>, W: Write + Seek>(
+    inputs: &[P],
+    owriter: W,
+    remap: bool,
+) -> std::io::Result<u64> {
+    if inputs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "no RAD files to merge"));
+    }
+    let mut readers = Vec::with_capacity(inputs.len());
+    for p in inputs.iter() {
+        readers.push(RadReader::from_path(p)?);
+    }
+
+    let first = &readers[0];
+    let mut ref_names = first.header.ref_names.clone();
+    let mut ref_idx: HashMap<String, u32> = ref_names
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (n.clone(), i as u32))
+        .collect();
+    // for each input, the new index of each of its references, if they
+    // have to be rewritten
+    let mut mappings: Vec<Option<Vec<u32>>> = Vec::with_capacity(readers.len());
+    for (p, rdr) in inputs.iter().zip(readers.iter()) {
+        let path = p.as_ref();
+        if rdr.header.is_paired != first.header.is_paired {
+            return Err(incompatible(
+                path,
+                "paired and unpaired files are mixed".to_string(),
+            ));
+        }
+        if rdr.file_tags != first.file_tags
+            || rdr.read_tags != first.read_tags
+            || rdr.aln_tags != first.aln_tags
+        {
+            return Err(incompatible(
+                path,
+                "the tag descriptions differ from those of the first file".to_string(),
+            ));
+        }
+        for name in ["cblen", "ulen", CODEC_TAG].iter() {
+            if rdr.file_tag_value(name) != first.file_tag_value(name) {
+                return Err(incompatible(
+                    path,
+                    format!(
+                        "{} is {:?} rather than {:?}",
+                        name,
+                        rdr.file_tag_value(name),
+                        first.file_tag_value(name)
+                    ),
+                ));
+            }
+        }
+
+        if rdr.header.ref_names == first.header.ref_names {
+            mappings.push(None);
+            continue;
+        }
+        if !remap {
+            return Err(incompatible(
+                path,
+                "the reference list differs from that of the first file".to_string(),
+            ));
+        }
+        let mapping = rdr
+            .header
+            .ref_names
+            .iter()
+            .map(|n| {
+                *ref_idx.entry(n.clone()).or_insert_with(|| {
+                    ref_names.push(n.clone());
+                    (ref_names.len() - 1) as u32
+                })
+            })
+            .collect();
+        mappings.push(Some(mapping));
+    }
+
+    let hdr = RADHeader {
+        is_paired: first.header.is_paired,
+        ref_count: ref_names.len() as u64,
+        ref_names,
+        num_chunks: 0,
+    };
+    // rewritten chunks are flushed explicitly, keeping their layout, and
+    // no index is written
+    let mut writer = RadWriter::new(
+        owriter,
+        &hdr,
+        &first.file_tags,
+        &first.file_tag_values,
+        &first.read_tags,
+        &first.aln_tags,
+    )?
+    .chunk_records(u32::MAX)
+    .index_barcodes(false);
+
+    for (rdr, mapping) in readers.iter_mut().zip(mappings.iter()) {
+        while let Some(raw) = rdr.next_declared_raw_chunk()? {
+            match mapping {
+                None => writer.write_raw_chunk(&raw)?,
+                Some(mapping) => {
+                    let mut chunk = rdr.decode_chunk(&raw)?;
+                    for rec in chunk.reads.iter_mut() {
+                        for t in rec.tids.iter_mut() {
+                            let new_id = mapping.get(ref_id(*t) as usize).ok_or_else(|| {
+                                Error::new(
+                                    ErrorKind::InvalidData,
+                                    format!("reference id {} is out of range", ref_id(*t)),
+                                )
+                            })?;
+                            *t = if is_fw(*t) { new_id | FW_MASK } else { *new_id };
+                        }
+                        writer.write_record(rec)?;
+                    }
+                    writer.flush_chunk()?;
+                }
+            }
+        }
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempPath, TestRad};
+    use crate::RADType;
+    use std::io::Cursor;
+
+    fn write_rad(
+        name: &str,
+        refs: &[&str],
+        ulen: u16,
+        reads: &[(u128, u64, Vec<u32>)],
+    ) -> TempPath {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .refs(refs)
+            .lengths(RADType::U32, 16, RADType::U32, ulen)
+            .writer(&mut out)
+            .chunk_records(1);
+        for (bc, umi, tids) in reads.iter() {
+            writer.write_read(*bc, *umi, tids).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let path = TempPath::new(&format!("merge-{}.rad", name));
+        std::fs::write(&path, out.into_inner()).unwrap();
+        path
+    }
+
+    fn read_all(bytes: Vec<u8>) -> (RADHeader, Vec<(u128, u64, Vec<u32>)>) {
+        let rdr = RadReader::new(Cursor::new(bytes)).unwrap();
+        let hdr = rdr.header.clone();
+        let reads = rdr
+            .flat_map(|c| c.unwrap().reads)
+            .map(|r| (r.bc, r.umi, r.tids))
+            .collect();
+        (hdr, reads)
+    }
+
+    #[test]
+    fn concatenates_chunks() {
+        let a = write_rad("a", &["txp1", "txp2"], 12, &[(1, 2, vec![0x80000000])]);
+        let b = write_rad(
+            "b",
+            &["txp1", "txp2"],
+            12,
+            &[(3, 4, vec![1]), (5, 6, vec![0])],
+        );
+        let mut out = Cursor::new(Vec::new());
+        assert_eq!(merge_rad(&[a, b], &mut out, false).unwrap(), 3);
+
+        let (hdr, reads) = read_all(out.into_inner());
+        assert_eq!(hdr.num_chunks, 3);
+        assert_eq!(
+            reads,
+            vec![(1, 2, vec![0x80000000]), (3, 4, vec![1]), (5, 6, vec![0])]
+        );
+    }
+
+    #[test]
+    fn rejects_incompatible_files() {
+        let a = write_rad("c", &["txp1", "txp2"], 12, &[(1, 2, vec![0])]);
+        let b = write_rad("d", &["txp1", "txp2"], 10, &[(1, 2, vec![0])]);
+        let c = write_rad("e", &["txp2", "txp1"], 12, &[(1, 2, vec![0])]);
+        let mut out = Cursor::new(Vec::new());
+        assert!(merge_rad(&[&a, &b], &mut out, true).is_err());
+        let mut out = Cursor::new(Vec::new());
+        assert!(merge_rad(&[&a, &c], &mut out, false).is_err());
+    }
+
+    #[test]
+    fn remaps_reference_ids() {
+        let a = write_rad("f", &["txp1", "txp2"], 12, &[(1, 2, vec![0x80000001])]);
+        let b = write_rad(
+            "g",
+            &["txp3", "txp2", "txp1"],
+            12,
+            &[(3, 4, vec![0x80000000, 1, 2])],
+        );
+        let mut out = Cursor::new(Vec::new());
+        merge_rad(&[a, b], &mut out, true).unwrap();
+
+        let (hdr, reads) = read_all(out.into_inner());
+        assert_eq!(hdr.ref_names, vec!["txp1", "txp2", "txp3"]);
+        assert_eq!(hdr.ref_count, 3);
+        assert_eq!(
+            reads,
+            vec![(1, 2, vec![0x80000001]), (3, 4, vec![0x80000002, 1, 0])]
+        );
+    }
+}
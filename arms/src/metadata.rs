@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::convert::ConvertSummary;
+use crate::RADIntID;
+
+/// The name alevin-fry expects for the RAD file of a mapping directory.
+pub const RAD_FILE: &str = "map.rad";
+/// The mapping summary written by piscem.
+pub const MAP_INFO_FILE: &str = "map_info.json";
+/// The mapping summary written by salmon, relative to the mapping directory.
+pub const META_INFO_FILE: &str = "aux_info/meta_info.json";
+/// The reads per barcode that were not mapped.
+pub const UNMAPPED_BC_FILE: &str = "unmapped_bc_count.bin";
+
+// the percentage of the input reads written to the RAD file
+fn percent_mapped(summary: &ConvertSummary) -> f64 {
+    if summary.input_reads == 0 {
+        0.0
+    } else {
+        100.0 * summary.num_written as f64 / summary.input_reads as f64
+    }
+}
+
+/// The contents of `map_info.json`, with the field names of piscem.
+pub fn map_info(summary: &ConvertSummary, ref_count: u64) -> Value {
+    json!({
+        "mapping_type": "sc_rna",
+        "tool": "fishgill",
+        "version": env!("CARGO_PKG_VERSION"),
+        "num_reads": summary.input_reads,
+        "num_mapped": summary.num_written,
+        "mapping_rate": percent_mapped(summary),
+        "num_targets": ref_count,
+        "cblen": summary.cblen,
+        "ulen": summary.ulen,
+    })
+}
+
+/// The contents of `aux_info/meta_info.json`, with the field names of
+/// salmon's alevin.
+pub fn meta_info(summary: &ConvertSummary, ref_count: u64) -> Value {
+    json!({
+        "tool": "fishgill",
+        "version": env!("CARGO_PKG_VERSION"),
+        "num_processed": summary.input_reads,
+        "num_mapped": summary.num_written,
+        "percent_mapped": percent_mapped(summary),
+        "num_decoy_fragments": 0,
+        "num_dovetail_fragments": 0,
+        "num_targets": ref_count,
+    })
+}
+
+/// Write the number of unmapped reads of each barcode as pairs of a u64
+/// barcode and a u32 count, little endian and sorted by barcode.
+pub fn write_unmapped_bc_counts<W: Write>(
+    counts: &HashMap<u64, u32>,
+    owriter: &mut W,
+) -> std::io::Result<()> {
+    let mut counts: Vec<(&u64, &u32)> = counts.iter().collect();
+    counts.sort_unstable();
+    for (bc, n) in counts {
+        owriter.write_all(&bc.to_le_bytes())?;
+        owriter.write_all(&n.to_le_bytes())?;
+    }
+    owriter.flush()
+}
+
+/// Read the counts written by `write_unmapped_bc_counts`.
+pub fn read_unmapped_bc_counts<R: Read>(reader: &mut R) -> std::io::Result<HashMap<u64, u32>> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let mut counts = HashMap::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        let bc = RADIntID::U64.read_from(&mut rest)?;
+        let n = RADIntID::U32.read_from(&mut rest)? as u32;
+        counts.insert(bc, n);
+    }
+    Ok(counts)
+}
+
+/// Write the files alevin-fry expects next to `map.rad` in the mapping
+/// directory `dir`: `map_info.json`, `aux_info/meta_info.json` and
+/// `unmapped_bc_count.bin`.
+pub fn write_alevin_fry_metadata<P: AsRef<Path>>(
+    dir: P,
+    summary: &ConvertSummary,
+    ref_count: u64,
+    unmapped: &HashMap<u64, u32>,
+) -> std::io::Result<()> {
+    let dir = dir.as_ref();
+    let write_json = |name: &str, value: Value| -> std::io::Result<()> {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut owriter = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut owriter, &value)?;
+        owriter.flush()
+    };
+    write_json(MAP_INFO_FILE, map_info(summary, ref_count))?;
+    write_json(META_INFO_FILE, meta_info(summary, ref_count))?;
+    let mut owriter = BufWriter::new(File::create(dir.join(UNMAPPED_BC_FILE))?);
+    write_unmapped_bc_counts(unmapped, &mut owriter)
+}
+
+/// Read the unmapped barcode counts of the mapping directory `dir`.
+pub fn unmapped_bc_counts_of<P: AsRef<Path>>(dir: P) -> std::io::Result<HashMap<u64, u32>> {
+    let mut reader = BufReader::new(File::open(dir.as_ref().join(UNMAPPED_BC_FILE))?);
+    read_unmapped_bc_counts(&mut reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    #[test]
+    fn writes_mapping_directory() {
+        let dir = TempPath::dir("metadata");
+        let summary = ConvertSummary {
+            input_reads: 8,
+            num_written: 6,
+            cblen: 16,
+            ulen: 12,
+            ..Default::default()
+        };
+        let unmapped: HashMap<u64, u32> = [(7, 1), (3, 2)].iter().cloned().collect();
+        write_alevin_fry_metadata(&dir, &summary, 5, &unmapped).unwrap();
+
+        let map_info: Value =
+            serde_json::from_reader(File::open(dir.join(MAP_INFO_FILE)).unwrap()).unwrap();
+        assert_eq!(map_info["num_reads"], json!(8));
+        assert_eq!(map_info["num_mapped"], json!(6));
+        assert_eq!(map_info["mapping_rate"], json!(75.0));
+        let meta_info: Value =
+            serde_json::from_reader(File::open(dir.join(META_INFO_FILE)).unwrap()).unwrap();
+        assert_eq!(meta_info["num_processed"], json!(8));
+        assert_eq!(meta_info["percent_mapped"], json!(75.0));
+
+        let bytes = std::fs::read(dir.join(UNMAPPED_BC_FILE)).unwrap();
+        assert_eq!(bytes.len(), 2 * 12);
+        assert_eq!(&bytes[..8], &3u64.to_le_bytes());
+        assert_eq!(unmapped_bc_counts_of(&dir).unwrap(), unmapped);
+    }
+}
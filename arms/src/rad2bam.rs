@@ -0,0 +1,245 @@
+use std::error::Error;
+use std::path::Path;
+
+use rust_htslib::bam;
+use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+
+use crate::convert::{u128_to_barcode_string, u64_to_barcode_string};
+use crate::reader::{is_fw, ref_id};
+use crate::RadReader;
+
+// RAD files do not store the reference lengths, so the @SQ lines claim the
+// largest length BAM allows
+const UNKNOWN_REF_LEN: u32 = i32::MAX as u32;
+
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_SECONDARY: u16 = 0x100;
+
+/// Write the reads of a RAD file as BAM (or SAM, when `bam_file` ends in
+/// `.sam`), returning the number of records written.
+///
+/// Each alignment becomes a record named after the index of its read, on
+/// the strand given by its orientation bit, with the CB and UR tags holding
+/// the decoded barcode and UMI and NH the number of alignments of the read.
+/// All but the first alignment of a read are flagged secondary. Records are
+/// placed at the `pos` alignment-level tag if the RAD file has one, and at
+/// the start of the reference otherwise. They carry no sequence and a
+/// placeholder CIGAR of `1M`, as records without one read back as unmapped.
+pub fn rad2bam(rad_file: &str, bam_file: &str, num_threads: u32) -> Result<u64, Box<dyn Error>> {
+    let mut rdr = RadReader::from_path(rad_file)?;
+    let cblen = rdr
+        .file_tag_value("cblen")
+        .and_then(|l| l.as_u64())
+        .ok_or("RAD file has no cblen file-level tag")? as usize;
+    let ulen = rdr
+        .file_tag_value("ulen")
+        .and_then(|l| l.as_u64())
+        .ok_or("RAD file has no ulen file-level tag")? as usize;
+    // the packed values hold at most 64 barcode and 32 UMI bases
+    if cblen > 64 {
+        return Err(format!("cannot decode barcodes of {} bases", cblen).into());
+    }
+    if ulen > 32 {
+        return Err(format!("cannot decode UMIs of {} bases", ulen).into());
+    }
+    let extra_tags: Vec<&str> = rdr
+        .aln_tags
+        .tags
+        .iter()
+        .map(|t| t.name.as_str())
+        .filter(|n| *n != "compressed_ori_refid")
+        .collect();
+    let num_extra = extra_tags.len();
+    let pos_idx = extra_tags.iter().position(|n| *n == "pos");
+
+    let mut header = bam::Header::new();
+    header.push_record(
+        bam::header::HeaderRecord::new(b"HD")
+            .push_tag(b"VN", &"1.6")
+            .push_tag(b"GO", &"query"),
+    );
+    for name in rdr.header.ref_names.iter() {
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", name)
+                .push_tag(b"LN", &UNKNOWN_REF_LEN),
+        );
+    }
+    let format = if Path::new(bam_file).extension().and_then(|e| e.to_str()) == Some("sam") {
+        bam::Format::SAM
+    } else {
+        bam::Format::BAM
+    };
+    let mut obam = bam::Writer::from_path(bam_file, &header, format)?;
+    obam.set_threads(num_threads.max(1) as usize)?;
+
+    let cigar = CigarString(vec![Cigar::Match(1)]);
+    let mut read_idx = 0u64;
+    let mut num_records = 0u64;
+    while let Some(chunk) = rdr.next_chunk()? {
+        for read in chunk.reads.iter() {
+            let qname = read_idx.to_string();
+            let cb = u128_to_barcode_string(read.bc, cblen);
+            let ur = u64_to_barcode_string(read.umi, ulen);
+            for (j, t) in read.tids.iter().enumerate() {
+                let mut rec = bam::Record::new();
+                rec.set(qname.as_bytes(), Some(&cigar), b"", b"");
+                rec.set_tid(ref_id(*t) as i32);
+                let pos = pos_idx
+                    .and_then(|p| read.aln_tags[j * num_extra + p].as_u64())
+                    .unwrap_or(0);
+                rec.set_pos(pos as i64);
+                rec.set_mapq(255);
+                let mut flags = 0;
+                if !is_fw(*t) {
+                    flags |= FLAG_REVERSE;
+                }
+                if j > 0 {
+                    flags |= FLAG_SECONDARY;
+                }
+                rec.set_flags(flags);
+                rec.set_mtid(-1);
+                rec.set_mpos(-1);
+                rec.set_insert_size(0);
+                rec.push_aux(b"CB", &Aux::String(cb.as_bytes()));
+                rec.push_aux(b"UR", &Aux::String(ur.as_bytes()));
+                rec.push_aux(b"NH", &Aux::Integer(read.tids.len() as i64));
+                obam.write(&rec)?;
+                num_records += 1;
+            }
+            read_idx += 1;
+        }
+    }
+    Ok(num_records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{bam2rad, ConvertOpts};
+    use crate::test_util::{TempPath, TestRad};
+    use crate::{RADType, TagValue};
+    use rust_htslib::bam::Read;
+    use std::io::Cursor;
+
+    #[test]
+    fn writes_one_record_per_alignment() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .lengths(RADType::U32, 4, RADType::U32, 2)
+            .aln_tag("pos", RADType::U32)
+            .writer(&mut out);
+        // ACGT, TG
+        writer
+            .write_read_with_tags(
+                0x1b,
+                0xe,
+                &[0x80000001, 0],
+                &[TagValue::U32(10), TagValue::U32(20)],
+            )
+            .unwrap();
+        writer.finish().unwrap();
+        let rad_file = TempPath::new("rad2bam.rad");
+        let sam_file = TempPath::new("rad2bam.sam");
+        std::fs::write(&rad_file, out.into_inner()).unwrap();
+
+        let n = rad2bam(rad_file.to_str().unwrap(), sam_file.to_str().unwrap(), 1).unwrap();
+        assert_eq!(n, 2);
+
+        let mut bam = bam::Reader::from_path(&sam_file).unwrap();
+        let names: Vec<&[u8]> = bam.header().target_names();
+        assert_eq!(names, vec![&b"txp1"[..], &b"txp2"[..]]);
+        let recs: Vec<bam::Record> = bam.records().map(|r| r.unwrap()).collect();
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs[0].qname(), b"0");
+        assert_eq!((recs[0].tid(), recs[0].pos()), (1, 10));
+        assert!(!recs[0].is_reverse() && !recs[0].is_secondary());
+        assert_eq!((recs[1].tid(), recs[1].pos()), (0, 20));
+        assert!(recs[1].is_reverse() && recs[1].is_secondary());
+        for r in recs.iter() {
+            assert_eq!(r.aux(b"CB").unwrap().string(), b"ACGT");
+            assert_eq!(r.aux(b"UR").unwrap().string(), b"TG");
+            assert_eq!(r.aux(b"NH").unwrap().integer(), 2);
+        }
+        // each record carries exactly the three tags
+        let sam = std::fs::read_to_string(&sam_file).unwrap();
+        assert!(sam
+            .lines()
+            .filter(|l| !l.starts_with('@'))
+            .all(|l| l.split('\t').count() == 14));
+    }
+
+    #[test]
+    fn rejects_barcode_lengths_beyond_the_packed_type() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .lengths(RADType::U128, 65, RADType::U32, 2)
+            .writer(&mut out);
+        writer.write_read(0x1b, 0xe, &[0x80000001]).unwrap();
+        writer.finish().unwrap();
+        let rad_file = TempPath::new("rad2bam-long.rad");
+        let sam_file = TempPath::new("rad2bam-long.sam");
+        std::fs::write(&rad_file, out.into_inner()).unwrap();
+
+        let e = rad2bam(rad_file.to_str().unwrap(), sam_file.to_str().unwrap(), 1).unwrap_err();
+        assert!(e.to_string().contains("65 bases"));
+        assert!(!sam_file.exists());
+    }
+
+    #[test]
+    fn converts_back_to_the_same_reads() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .lengths(RADType::U32, 4, RADType::U32, 2)
+            .aln_tag("pos", RADType::U32)
+            .writer(&mut out);
+        writer
+            .write_read_with_tags(
+                0x1b,
+                0xe,
+                &[0x80000001, 0],
+                &[TagValue::U32(10), TagValue::U32(20)],
+            )
+            .unwrap();
+        writer
+            .write_read_with_tags(0xe4, 0x1, &[0x80000000], &[TagValue::U32(30)])
+            .unwrap();
+        writer.finish().unwrap();
+        let rad_file = TempPath::new("rad2sam.rad");
+        let sam_file = TempPath::new("rad2sam.sam");
+        let back_file = TempPath::new("sam2rad.rad");
+        std::fs::write(&rad_file, out.into_inner()).unwrap();
+        rad2bam(rad_file.to_str().unwrap(), sam_file.to_str().unwrap(), 1).unwrap();
+
+        let opts = ConvertOpts {
+            positions: true,
+            ..Default::default()
+        };
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let summary = bam2rad(
+            sam_file.to_str().unwrap().to_string(),
+            back_file.to_str().unwrap().to_string(),
+            1,
+            &opts,
+            &log,
+        )
+        .unwrap();
+        assert_eq!(summary.num_written, 2);
+
+        let mut rdr = RadReader::from_path(&back_file).unwrap();
+        let mut reads = vec![];
+        while let Some(chunk) = rdr.next_chunk().unwrap() {
+            reads.extend(chunk.reads);
+        }
+        assert_eq!(reads.len(), 2);
+        assert_eq!((reads[0].bc, reads[0].umi), (0x1b, 0xe));
+        assert_eq!(reads[0].tids, vec![0x80000001, 0]);
+        assert_eq!((reads[1].bc, reads[1].umi), (0xe4, 0x1));
+        assert_eq!(reads[1].tids, vec![0x80000000]);
+        let pos: Vec<Option<u64>> = reads
+            .iter()
+            .flat_map(|r| r.aln_tags.iter().map(|t| t.as_u64()))
+            .collect();
+        assert_eq!(pos, vec![Some(10), Some(20), Some(30)]);
+    }
+}
@@ -0,0 +1,470 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::codec::Codec;
+use crate::index::RadIndex;
+use crate::{RADHeader, RADIntID, TagDesc, TagSection, TagValue};
+
+/// The bit of a compressed reference id that is set for alignments on
+/// the forward strand.
+pub const FW_MASK: u32 = 0x80000000;
+
+/// The reference index of a compressed reference id.
+pub fn ref_id(tid: u32) -> u32 {
+    tid & !FW_MASK
+}
+
+/// Whether a compressed reference id refers to the forward strand.
+pub fn is_fw(tid: u32) -> bool {
+    tid & FW_MASK != 0
+}
+
+/// A single read (or fragment) stored in a RAD chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadRecord {
+    /// the packed barcode, which takes 128 bits for barcodes of more than
+    /// 32 bases
+    pub bc: u128,
+    pub umi: u64,
+    /// the reference ids of the alignments of this read; the highest
+    /// bit (0x80000000) is set when the alignment is on the forward strand
+    pub tids: Vec<u32>,
+    /// the values of the alignment-level tags other than the reference id,
+    /// in header order, for one alignment after the other
+    pub aln_tags: Vec<TagValue>,
+}
+
+/// A chunk whose records have not been decoded yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawChunk {
+    pub nbytes: u32,
+    pub nrec: u32,
+    /// the records, i.e. the `nbytes - 8` bytes after `nbytes` and `nrec`,
+    /// still compressed if the file is
+    pub data: Vec<u8>,
+}
+
+/// A chunk of read records, as it is laid out in the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub nbytes: u32,
+    pub nrec: u32,
+    pub reads: Vec<ReadRecord>,
+}
+
+/// Reader for RAD files, such as those written by `convert::bam2rad`.
+///
+/// Creating the reader parses the header and the tag sections; the chunks
+/// are then obtained by iterating over the reader.
+pub struct RadReader<R: Read> {
+    reader: R,
+    pub header: RADHeader,
+    pub file_tags: TagSection,
+    pub read_tags: TagSection,
+    pub aln_tags: TagSection,
+    pub file_tag_values: Vec<TagValue>,
+    bc_idx: usize,
+    umi_idx: usize,
+    refid_idx: usize,
+    codec: Codec,
+    chunks_read: u64,
+    pos: u64,
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn find_tag(tags: &TagSection, name: &str) -> std::io::Result<usize> {
+    tags.position(name)
+        .ok_or_else(|| invalid_data(format!("RAD file has no {:?} tag", name)))
+}
+
+/// Read a single integer (or boolean) tag value of the type of `tag`.
+fn read_int_value<R: Read>(tag: &TagDesc, reader: &mut R) -> std::io::Result<u128> {
+    match tag.typeid.int_id() {
+        Some(int_id) => int_id.read_u128_from(reader),
+        None => Err(invalid_data(format!(
+            "cannot decode {:?} value of tag {} as an integer",
+            tag.typeid, tag.name
+        ))),
+    }
+}
+
+impl RadReader<BufReader<File>> {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        RadReader::new(BufReader::with_capacity(1048576, file))
+    }
+}
+
+impl<R: Read> RadReader<R> {
+    /// Parse the header and tag sections from `reader`, leaving it
+    /// positioned at the first chunk.
+    pub fn new(mut reader: R) -> std::io::Result<Self> {
+        let header = RADHeader::read_from(&mut reader)?;
+        let file_tags = TagSection::read_from(&mut reader)?;
+        let read_tags = TagSection::read_from(&mut reader)?;
+        let aln_tags = TagSection::read_from(&mut reader)?;
+        // the values of the file-level tags follow their descriptions
+        let file_tag_values = file_tags.read_values(&mut reader)?;
+
+        let bc_idx = find_tag(&read_tags, "b")?;
+        let umi_idx = find_tag(&read_tags, "u")?;
+        let refid_idx = find_tag(&aln_tags, "compressed_ori_refid")?;
+        let codec = Codec::from_file_tags(&file_tags, &file_tag_values)?;
+        let pos = (header.get_size()
+            + file_tags.get_size()
+            + read_tags.get_size()
+            + aln_tags.get_size()
+            + file_tags.values_size()) as u64;
+
+        Ok(RadReader {
+            reader,
+            header,
+            file_tags,
+            read_tags,
+            aln_tags,
+            file_tag_values,
+            bc_idx,
+            umi_idx,
+            refid_idx,
+            codec,
+            chunks_read: 0,
+            pos,
+        })
+    }
+
+    /// The value of the file-level tag `name`, if there is one.
+    pub fn file_tag_value(&self, name: &str) -> Option<&TagValue> {
+        self.file_tags
+            .position(name)
+            .map(|idx| &self.file_tag_values[idx])
+    }
+
+    fn read_record(&self, data: &mut Cursor<&[u8]>) -> std::io::Result<ReadRecord> {
+        let na = RADIntID::U32.read_from(data)?;
+        let mut bc = 0u128;
+        let mut umi = 0u64;
+        for (idx, tag) in self.read_tags.tags.iter().enumerate() {
+            let v = read_int_value(tag, data)?;
+            if idx == self.bc_idx {
+                bc = v;
+            } else if idx == self.umi_idx {
+                umi = u64::try_from(v)
+                    .map_err(|_| invalid_data(format!("UMI {} is wider than 64 bits", v)))?;
+            }
+        }
+
+        // check the number of alignments against the bytes left before
+        // making room for them, as a corrupt count can be huge
+        let left = data.get_ref().len() as u64 - data.position();
+        if na * self.aln_tags.values_size() as u64 > left {
+            return Err(invalid_data(format!(
+                "{} alignments do not fit into the {} bytes left",
+                na, left
+            )));
+        }
+        let mut tids = Vec::with_capacity(na as usize);
+        let mut aln_tags = Vec::with_capacity((self.aln_tags.tags.len() - 1) * na as usize);
+        for _ in 0..na {
+            for (idx, tag) in self.aln_tags.tags.iter().enumerate() {
+                if idx == self.refid_idx {
+                    tids.push(read_int_value(tag, data)? as u32);
+                } else {
+                    aln_tags.push(TagValue::read_from(tag.typeid, data)?);
+                }
+            }
+        }
+        Ok(ReadRecord {
+            bc,
+            umi,
+            tids,
+            aln_tags,
+        })
+    }
+
+    /// How the chunks of the file are compressed.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// The byte offset of the next chunk in the file.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// The number of chunks read so far.
+    pub fn chunks_read(&self) -> u64 {
+        self.chunks_read
+    }
+
+    /// Read the next chunk without decoding its records. Unlike
+    /// `next_declared_raw_chunk`, this ignores `num_chunks` and reads until
+    /// the end of the input; a chunk cut short by the end of the input is
+    /// an error.
+    pub fn next_raw_chunk(&mut self) -> std::io::Result<Option<RawChunk>> {
+        let mut buf = [0u8; 8];
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if filled == 0 {
+            return Ok(None);
+        } else if filled < buf.len() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("chunk {} is truncated", self.chunks_read),
+            ));
+        }
+        let nbytes = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let nrec = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        // nbytes includes the two u32 values we have just read
+        if nbytes < 8 {
+            return Err(invalid_data(format!(
+                "chunk {} has invalid size {}",
+                self.chunks_read, nbytes
+            )));
+        }
+        let mut data = vec![0u8; nbytes as usize - 8];
+        self.reader.read_exact(&mut data).map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                Error::new(e.kind(), format!("chunk {} is truncated", self.chunks_read))
+            } else {
+                e
+            }
+        })?;
+
+        self.chunks_read += 1;
+        self.pos += nbytes as u64;
+        Ok(Some(RawChunk { nbytes, nrec, data }))
+    }
+
+    /// Decode the records of a chunk, checking that they take up
+    /// exactly the chunk's bytes.
+    pub fn decode_chunk(&self, raw: &RawChunk) -> std::io::Result<Chunk> {
+        let records = match self.codec {
+            Codec::None => None,
+            _ => Some(self.codec.decompress(&raw.data)?),
+        };
+        let records = records.as_deref().unwrap_or(&raw.data[..]);
+        let mut data = Cursor::new(records);
+        // a corrupt nrec must not size the vector: no more records than
+        // the smallest record fits into the bytes can be read
+        let min_record = 4 + self.read_tags.values_size();
+        let mut reads = Vec::with_capacity((raw.nrec as usize).min(records.len() / min_record));
+        for i in 0..raw.nrec {
+            let rec = self.read_record(&mut data).map_err(|e| {
+                invalid_data(format!(
+                    "record {} of {} overruns the chunk's {} bytes ({})",
+                    i, raw.nrec, raw.nbytes, e
+                ))
+            })?;
+            reads.push(rec);
+        }
+        if data.position() != records.len() as u64 {
+            return Err(invalid_data(format!(
+                "{} bytes left over after {} records",
+                records.len() as u64 - data.position(),
+                raw.nrec
+            )));
+        }
+        Ok(Chunk {
+            nbytes: raw.nbytes,
+            nrec: raw.nrec,
+            reads,
+        })
+    }
+
+    /// Whether the header leaves the number of chunks open, i.e.
+    /// `num_chunks` is 0, as in files written to a pipe (see
+    /// `RadWriter::finish_stream`). The chunks of such files are read up
+    /// to the end of the input.
+    pub fn is_streamed(&self) -> bool {
+        self.header.num_chunks == 0
+    }
+
+    /// Read the next chunk without decoding its records, or return `None`
+    /// once all `num_chunks` chunks declared in the header have been read,
+    /// or at the end of the input if the file is streamed.
+    pub fn next_declared_raw_chunk(&mut self) -> std::io::Result<Option<RawChunk>> {
+        if self.is_streamed() {
+            return self.next_raw_chunk();
+        }
+        if self.chunks_read >= self.header.num_chunks {
+            return Ok(None);
+        }
+        let idx = self.chunks_read;
+        self.next_raw_chunk()?.map(Some).ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "expected {} chunks but the file ends after {}",
+                    self.header.num_chunks, idx
+                ),
+            )
+        })
+    }
+
+    /// Read the next chunk, or return `None` once all chunks have been
+    /// read (see `next_declared_raw_chunk`).
+    pub fn next_chunk(&mut self) -> std::io::Result<Option<Chunk>> {
+        let idx = self.chunks_read;
+        match self.next_declared_raw_chunk()? {
+            Some(raw) => self
+                .decode_chunk(&raw)
+                .map(Some)
+                .map_err(|e| invalid_data(format!("chunk {}: {}", idx, e))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<R: Read + Seek> RadReader<R> {
+    /// Position the reader at chunk `k` of `index`, so that the next call
+    /// to `next_chunk` reads it.
+    pub fn seek_to_chunk(&mut self, index: &RadIndex, k: usize) -> std::io::Result<()> {
+        let entry = index.chunks.get(k).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("the index has no chunk {}", k),
+            )
+        })?;
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        self.pos = entry.offset;
+        self.chunks_read = k as u64;
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for RadReader<R> {
+    type Item = std::io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RADType;
+    use std::io::Write;
+
+    // lay out a small file the same way `bam2rad` does
+    fn example_rad() -> Vec<u8> {
+        let mut data = Cursor::new(vec![]);
+        let hdr = RADHeader {
+            is_paired: 0,
+            ref_count: 2,
+            ref_names: vec!["txp1".to_string(), "txp2".to_string()],
+            num_chunks: 1,
+        };
+        hdr.write_to(&mut data).unwrap();
+
+        let mut file_tags = TagSection::new();
+        file_tags.add_tag("cblen", RADType::U16);
+        file_tags.add_tag("ulen", RADType::U16);
+        let mut read_tags = TagSection::new();
+        read_tags.add_tag("b", RADType::U32);
+        read_tags.add_tag("u", RADType::U16);
+        let mut aln_tags = TagSection::new();
+        aln_tags.add_tag("compressed_ori_refid", RADType::U32);
+        file_tags.write_to(&mut data).unwrap();
+        read_tags.write_to(&mut data).unwrap();
+        aln_tags.write_to(&mut data).unwrap();
+        file_tags
+            .write_values(&[TagValue::U16(16), TagValue::U16(8)], &mut data)
+            .unwrap();
+
+        // one chunk with two reads
+        let nbytes = 8 + (4 + 4 + 2 + 2 * 4) + (4 + 4 + 2 + 4);
+        data.write_all(&(nbytes as u32).to_le_bytes()).unwrap();
+        data.write_all(&2u32.to_le_bytes()).unwrap();
+        data.write_all(&2u32.to_le_bytes()).unwrap();
+        data.write_all(&7u32.to_le_bytes()).unwrap();
+        data.write_all(&9u16.to_le_bytes()).unwrap();
+        data.write_all(&0x80000000u32.to_le_bytes()).unwrap();
+        data.write_all(&1u32.to_le_bytes()).unwrap();
+        data.write_all(&1u32.to_le_bytes()).unwrap();
+        data.write_all(&8u32.to_le_bytes()).unwrap();
+        data.write_all(&10u16.to_le_bytes()).unwrap();
+        data.write_all(&0x80000001u32.to_le_bytes()).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn reads_header_and_chunks() {
+        let mut rdr = RadReader::new(Cursor::new(example_rad())).unwrap();
+        assert_eq!(rdr.header.is_paired, 0);
+        assert_eq!(rdr.header.ref_names, vec!["txp1", "txp2"]);
+        assert_eq!(rdr.header.num_chunks, 1);
+        assert_eq!(rdr.read_tags.tags[0], TagDesc::new("b", RADType::U32));
+        assert_eq!(rdr.file_tag_value("cblen"), Some(&TagValue::U16(16)));
+        assert_eq!(rdr.file_tag_value("ulen"), Some(&TagValue::U16(8)));
+
+        let chunk = rdr.next_chunk().unwrap().unwrap();
+        assert_eq!(chunk.nrec, 2);
+        assert_eq!(
+            chunk.reads[0],
+            ReadRecord {
+                bc: 7,
+                umi: 9,
+                tids: vec![0x80000000, 1],
+                aln_tags: vec![]
+            }
+        );
+        assert_eq!(chunk.reads[1].tids, vec![0x80000001]);
+        assert!(rdr.next_chunk().unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_streamed_file_to_the_end() {
+        let mut bytes = example_rad();
+        let hdr = RADHeader::read_from(&mut Cursor::new(&bytes)).unwrap();
+        let offset = hdr.num_chunks_offset() as usize;
+        bytes[offset..offset + 8].copy_from_slice(&0u64.to_le_bytes());
+        // a second copy of the chunk
+        let nbytes = 8 + (4 + 4 + 2 + 2 * 4) + (4 + 4 + 2 + 4);
+        let chunk = bytes[bytes.len() - nbytes..].to_vec();
+        bytes.extend(chunk);
+
+        let rdr = RadReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert!(rdr.is_streamed());
+        let chunks: Vec<Chunk> = rdr.map(|c| c.unwrap()).collect();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].reads[1].tids, vec![0x80000001]);
+
+        bytes.truncate(bytes.len() - 2);
+        let rdr = RadReader::new(Cursor::new(bytes)).unwrap();
+        assert!(rdr.collect::<std::io::Result<Vec<Chunk>>>().is_err());
+    }
+
+    #[test]
+    fn rejects_alignment_count_beyond_the_chunk() {
+        let mut bytes = example_rad();
+        // the number of alignments of the first read
+        let nbytes = 8 + (4 + 4 + 2 + 2 * 4) + (4 + 4 + 2 + 4);
+        let offset = bytes.len() - nbytes + 8;
+        bytes[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut rdr = RadReader::new(Cursor::new(bytes)).unwrap();
+        let e = rdr.next_chunk().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_chunk_is_an_error() {
+        let mut bytes = example_rad();
+        bytes.truncate(bytes.len() - 2);
+        let rdr = RadReader::new(Cursor::new(bytes)).unwrap();
+        let chunks: std::io::Result<Vec<Chunk>> = rdr.collect();
+        assert!(chunks.is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
+
+use serde_json::{json, Value};
+
+use crate::reader::{is_fw, ref_id};
+use crate::RadReader;
+
+/// Output formats of `stats`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+}
+
+/// Summary statistics of the reads in a RAD file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RadStats {
+    pub num_chunks: u64,
+    pub num_reads: u64,
+    pub num_alignments: u64,
+    /// the number of reads for each number of alignments
+    pub alns_per_read: BTreeMap<usize, u64>,
+    pub num_barcodes: u64,
+    /// the number of distinct (barcode, UMI) pairs, as a UMI is only
+    /// meaningful within its cell
+    pub num_umis: u64,
+    pub num_fw: u64,
+    pub num_rc: u64,
+    /// the reference names and the number of alignments to each of them
+    pub ref_names: Vec<String>,
+    pub ref_hits: Vec<u64>,
+}
+
+impl RadStats {
+    /// Gather the statistics of all chunks of `rdr`.
+    pub fn from_reader<R: Read>(mut rdr: RadReader<R>) -> std::io::Result<RadStats> {
+        let mut stats = RadStats {
+            ref_hits: vec![0; rdr.header.ref_names.len()],
+            ref_names: rdr.header.ref_names.clone(),
+            ..Default::default()
+        };
+        let mut barcodes = HashSet::new();
+        let mut umis = HashSet::new();
+
+        while let Some(chunk) = rdr.next_chunk()? {
+            stats.num_chunks += 1;
+            for rec in chunk.reads.iter() {
+                stats.num_reads += 1;
+                stats.num_alignments += rec.tids.len() as u64;
+                *stats.alns_per_read.entry(rec.tids.len()).or_insert(0) += 1;
+                barcodes.insert(rec.bc);
+                umis.insert((rec.bc, rec.umi));
+                for t in rec.tids.iter() {
+                    if is_fw(*t) {
+                        stats.num_fw += 1;
+                    } else {
+                        stats.num_rc += 1;
+                    }
+                    if let Some(h) = stats.ref_hits.get_mut(ref_id(*t) as usize) {
+                        *h += 1;
+                    }
+                }
+            }
+        }
+        stats.num_barcodes = barcodes.len() as u64;
+        stats.num_umis = umis.len() as u64;
+        Ok(stats)
+    }
+
+    /// The fraction of alignments on the forward strand.
+    pub fn fw_fraction(&self) -> f64 {
+        if self.num_alignments == 0 {
+            0.0
+        } else {
+            self.num_fw as f64 / self.num_alignments as f64
+        }
+    }
+
+    /// The `n` references with the most alignments, most hits first.
+    pub fn top_refs(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut refs: Vec<(&str, u64)> = self
+            .ref_names
+            .iter()
+            .map(|n| n.as_str())
+            .zip(self.ref_hits.iter().cloned())
+            .filter(|(_, h)| *h > 0)
+            .collect();
+        refs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        refs.truncate(n);
+        refs
+    }
+
+    pub fn to_json(&self, top: usize) -> Value {
+        let alns_per_read: serde_json::Map<String, Value> = self
+            .alns_per_read
+            .iter()
+            .map(|(na, c)| (na.to_string(), json!(c)))
+            .collect();
+        let top_refs: Vec<Value> = self
+            .top_refs(top)
+            .iter()
+            .map(|(n, h)| json!({"ref": n, "hits": h}))
+            .collect();
+        json!({
+            "num_chunks": self.num_chunks,
+            "num_reads": self.num_reads,
+            "num_alignments": self.num_alignments,
+            "alns_per_read": alns_per_read,
+            "num_barcodes": self.num_barcodes,
+            "num_umis": self.num_umis,
+            "num_fw": self.num_fw,
+            "num_rc": self.num_rc,
+            "fw_fraction": self.fw_fraction(),
+            "top_refs": top_refs,
+        })
+    }
+
+    pub fn write_text<W: Write>(&self, top: usize, out: &mut W) -> std::io::Result<()> {
+        writeln!(out, "chunks\t{}", self.num_chunks)?;
+        writeln!(out, "reads\t{}", self.num_reads)?;
+        writeln!(out, "alignments\t{}", self.num_alignments)?;
+        writeln!(out, "distinct barcodes\t{}", self.num_barcodes)?;
+        writeln!(out, "distinct UMIs per barcode\t{}", self.num_umis)?;
+        writeln!(
+            out,
+            "forward/reverse\t{}/{} ({:.4} forward)",
+            self.num_fw,
+            self.num_rc,
+            self.fw_fraction()
+        )?;
+        writeln!(out, "\nalignments per read\treads")?;
+        for (na, c) in self.alns_per_read.iter() {
+            writeln!(out, "{}\t{}", na, c)?;
+        }
+        writeln!(out, "\ntop references\thits")?;
+        for (n, h) in self.top_refs(top).iter() {
+            writeln!(out, "{}\t{}", n, h)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the statistics of a RAD file in `format`, listing the `top`
+/// references with the most alignments.
+pub fn stats<R: Read, W: Write>(
+    rdr: RadReader<R>,
+    format: StatsFormat,
+    top: usize,
+    out: &mut W,
+) -> std::io::Result<()> {
+    let stats = RadStats::from_reader(rdr)?;
+    match format {
+        StatsFormat::Text => stats.write_text(top, out)?,
+        StatsFormat::Json => writeln!(out, "{}", stats.to_json(top))?,
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestRad;
+    use std::io::Cursor;
+
+    #[test]
+    fn summarizes_reads() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .refs(&["txp1", "txp2", "txp3"])
+            .writer(&mut out)
+            .chunk_records(2);
+        writer.write_read(1, 10, &[0x80000000, 1]).unwrap();
+        writer.write_read(1, 11, &[0x80000001]).unwrap();
+        writer.write_read(2, 10, &[0x80000001]).unwrap();
+        writer.finish().unwrap();
+        out.set_position(0);
+
+        let stats = RadStats::from_reader(RadReader::new(out).unwrap()).unwrap();
+        assert_eq!(stats.num_chunks, 2);
+        assert_eq!(stats.num_reads, 3);
+        assert_eq!(stats.num_alignments, 4);
+        assert_eq!(stats.alns_per_read.get(&1), Some(&2));
+        assert_eq!(stats.alns_per_read.get(&2), Some(&1));
+        assert_eq!(stats.num_barcodes, 2);
+        // UMI 10 of barcodes 1 and 2 are different molecules
+        assert_eq!(stats.num_umis, 3);
+        assert_eq!((stats.num_fw, stats.num_rc), (3, 1));
+        assert_eq!(stats.top_refs(5), vec![("txp2", 3), ("txp1", 1)]);
+
+        let js = stats.to_json(1);
+        assert_eq!(js["alns_per_read"]["2"], json!(1));
+        assert_eq!(js["top_refs"], json!([{"ref": "txp2", "hits": 3}]));
+    }
+}
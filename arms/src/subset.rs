@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, Write};
+use std::path::Path;
+
+use crate::convert::{cb_string_to_u128_with, AmbiguousBases};
+use crate::{RadReader, RadWriter};
+
+/// Pack a barcode from a barcode list the way `bam2rad` packs them,
+/// ignoring a suffix such as the `-1` of Cell Ranger's `barcodes.tsv`.
+/// With `cblen`, the barcode must have that length.
+pub(crate) fn parse_barcode(field: &str, cblen: Option<usize>) -> Result<u128, String> {
+    let bc = field.split('-').next().unwrap();
+    if !bc.bytes().all(|nt| b"ACGT".contains(&nt)) {
+        return Err(format!("{:?} is not a barcode", bc));
+    }
+    match cblen {
+        Some(l) if bc.len() != l => {
+            return Err(format!(
+                "barcode {} does not have the length {} of the RAD file's barcodes",
+                bc, l
+            ));
+        }
+        _ if bc.len() > 64 => {
+            return Err(format!("barcode {} is longer than 64 bases", bc));
+        }
+        _ => (),
+    }
+    cb_string_to_u128_with(bc.as_bytes(), AmbiguousBases::Reject).map_err(|e| e.to_string())
+}
+
+/// Read a list of cell barcodes, one per line, packed the way `bam2rad`
+/// packs them. Only the first whitespace-separated field of each line is
+/// used, and a suffix such as the `-1` of Cell Ranger's `barcodes.tsv` is
+/// ignored. With `cblen`, all barcodes must have that length.
+pub fn read_barcode_list<P: AsRef<Path>>(
+    path: P,
+    cblen: Option<usize>,
+) -> std::io::Result<HashSet<u128>> {
+    let rdr = BufReader::new(File::open(path)?);
+    let mut barcodes = HashSet::new();
+    for (lnum, line) in rdr.lines().enumerate() {
+        let line = line?;
+        let field = match line.split_whitespace().next() {
+            Some(field) => field,
+            None => continue,
+        };
+        let bc = parse_barcode(field, cblen).map_err(|msg| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("line {} of the barcode list: {}", lnum + 1, msg),
+            )
+        })?;
+        barcodes.insert(bc);
+    }
+    Ok(barcodes)
+}
+
+/// Copy the reads of `rdr` whose barcode is in `barcodes` to `owriter`,
+/// returning the number of reads kept and the number of reads read.
+pub fn subset_rad<R: Read, W: Write + Seek>(
+    mut rdr: RadReader<R>,
+    barcodes: &HashSet<u128>,
+    owriter: W,
+) -> std::io::Result<(u64, u64)> {
+    let mut writer = RadWriter::with_header_of(owriter, &rdr)?;
+    let mut num_kept = 0;
+    let mut num_reads = 0;
+    while let Some(chunk) = rdr.next_chunk()? {
+        for rec in chunk.reads.iter() {
+            num_reads += 1;
+            if barcodes.contains(&rec.bc) {
+                num_kept += 1;
+                writer.write_record(rec)?;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok((num_kept, num_reads))
+}
+
+/// The part out of `parts` that the reads of barcode `bc` belong to.
+///
+/// This uses the finalizer of splitmix64, so that the assignment does not
+/// depend on the platform or the version of the standard library. The two
+/// halves of barcodes wider than 64 bits are combined first.
+pub fn barcode_part(bc: u128, parts: usize) -> usize {
+    let folded = (bc as u64) ^ ((bc >> 64) as u64);
+    let mut h = folded.wrapping_add(0x9e3779b97f4a7c15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^= h >> 31;
+    (h % parts as u64) as usize
+}
+
+/// Partition the reads of `rdr` by barcode into one RAD file per writer in
+/// `owriters`, returning the number of reads written to each of them. All
+/// reads of a barcode end up in the same part.
+pub fn split_rad<R: Read, W: Write + Seek>(
+    mut rdr: RadReader<R>,
+    owriters: Vec<W>,
+) -> std::io::Result<Vec<u64>> {
+    if owriters.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cannot split into 0 parts",
+        ));
+    }
+    let mut writers = Vec::with_capacity(owriters.len());
+    for owriter in owriters.into_iter() {
+        writers.push(RadWriter::with_header_of(owriter, &rdr)?);
+    }
+    let mut counts = vec![0u64; writers.len()];
+    while let Some(chunk) = rdr.next_chunk()? {
+        for rec in chunk.reads.iter() {
+            let part = barcode_part(rec.bc, writers.len());
+            counts[part] += 1;
+            writers[part].write_record(rec)?;
+        }
+    }
+    for writer in writers.into_iter() {
+        writer.finish()?;
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempPath, TestRad};
+    use crate::RADType;
+    use std::io::Cursor;
+
+    fn example_rad() -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .lengths(RADType::U8, 4, RADType::U8, 2)
+            .writer(&mut out)
+            .chunk_records(2);
+        for i in 0..20 {
+            writer.write_read(i as u128 % 5, i, &[0x80000000]).unwrap();
+        }
+        writer.finish().unwrap();
+        out.into_inner()
+    }
+
+    fn barcodes_of(bytes: Vec<u8>) -> Vec<u128> {
+        let rdr = RadReader::new(Cursor::new(bytes)).unwrap();
+        rdr.flat_map(|c| c.unwrap().reads).map(|r| r.bc).collect()
+    }
+
+    #[test]
+    fn reads_barcode_list() {
+        let path = TempPath::new("subset-barcodes.txt");
+        std::fs::write(&path, "AAAC-1\nAACA\t17\n\nAAAT\n").unwrap();
+        let barcodes = read_barcode_list(&path, Some(4)).unwrap();
+        assert_eq!(barcodes, [1, 4, 3].iter().cloned().collect());
+        assert!(read_barcode_list(&path, Some(5)).is_err());
+
+        std::fs::write(&path, "AANC\n").unwrap();
+        assert!(read_barcode_list(&path, None).is_err());
+    }
+
+    #[test]
+    fn subsets_by_barcode() {
+        let rdr = RadReader::new(Cursor::new(example_rad())).unwrap();
+        let barcodes = [1, 3].iter().cloned().collect();
+        let mut out = Cursor::new(Vec::new());
+        assert_eq!(subset_rad(rdr, &barcodes, &mut out).unwrap(), (8, 20));
+        let kept = barcodes_of(out.into_inner());
+        assert_eq!(kept.len(), 8);
+        assert!(kept.iter().all(|bc| *bc == 1 || *bc == 3));
+    }
+
+    #[test]
+    fn splits_by_barcode() {
+        let rdr = RadReader::new(Cursor::new(example_rad())).unwrap();
+        let mut outs = vec![Cursor::new(Vec::new()), Cursor::new(Vec::new())];
+        let counts = split_rad(rdr, outs.iter_mut().collect()).unwrap();
+        assert_eq!(counts.iter().sum::<u64>(), 20);
+
+        for (part, out) in outs.into_iter().enumerate() {
+            let bcs = barcodes_of(out.into_inner());
+            assert_eq!(bcs.len() as u64, counts[part]);
+            assert!(bcs.iter().all(|bc| barcode_part(*bc, 2) == part));
+        }
+    }
+}
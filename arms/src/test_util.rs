@@ -0,0 +1,154 @@
+//! Fixtures shared by the tests of the RAD modules.
+
+use std::io::Write;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use crate::{RADHeader, RADType, RadWriter, TagSection, TagValue};
+
+/// The header and tag sections of the small RAD files written by the
+/// tests. By default the file has the references txp1 and txp2, u32
+/// barcodes and UMIs declared as 16 and 12 bases long, and the reference
+/// id as its only alignment-level tag.
+pub struct TestRad {
+    pub header: RADHeader,
+    pub file_tags: TagSection,
+    pub file_tag_values: Vec<TagValue>,
+    pub read_tags: TagSection,
+    pub aln_tags: TagSection,
+}
+
+impl Default for TestRad {
+    fn default() -> Self {
+        let mut file_tags = TagSection::new();
+        file_tags.add_tag("cblen", RADType::U16);
+        file_tags.add_tag("ulen", RADType::U16);
+        let mut aln_tags = TagSection::new();
+        aln_tags.add_tag("compressed_ori_refid", RADType::U32);
+        TestRad {
+            header: RADHeader {
+                is_paired: 0,
+                ref_count: 0,
+                ref_names: vec![],
+                num_chunks: 0,
+            },
+            file_tags,
+            file_tag_values: vec![TagValue::U16(0), TagValue::U16(0)],
+            read_tags: TagSection::new(),
+            aln_tags,
+        }
+        .refs(&["txp1", "txp2"])
+        .lengths(RADType::U32, 16, RADType::U32, 12)
+    }
+}
+
+impl TestRad {
+    pub fn refs(mut self, names: &[&str]) -> Self {
+        self.header.ref_count = names.len() as u64;
+        self.header.ref_names = names.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    /// Set the types of the barcode and UMI and their declared lengths.
+    pub fn lengths(mut self, bc_type: RADType, cblen: u16, umi_type: RADType, ulen: u16) -> Self {
+        self.read_tags = TagSection::new();
+        self.read_tags.add_tag("b", bc_type);
+        self.read_tags.add_tag("u", umi_type);
+        self.file_tag_values[0] = TagValue::U16(cblen);
+        self.file_tag_values[1] = TagValue::U16(ulen);
+        self
+    }
+
+    pub fn file_tag(mut self, name: &str, typeid: RADType, value: TagValue) -> Self {
+        self.file_tags.add_tag(name, typeid);
+        self.file_tag_values.push(value);
+        self
+    }
+
+    pub fn aln_tag(mut self, name: &str, typeid: RADType) -> Self {
+        self.aln_tags.add_tag(name, typeid);
+        self
+    }
+
+    /// A writer of a file with these header and tag sections.
+    pub fn writer<W: Write>(&self, owriter: W) -> RadWriter<W> {
+        RadWriter::new(
+            owriter,
+            &self.header,
+            &self.file_tags,
+            &self.file_tag_values,
+            &self.read_tags,
+            &self.aln_tags,
+        )
+        .unwrap()
+    }
+}
+
+/// A path in the temporary directory, unique to the test process, that is
+/// removed with everything in it when dropped.
+pub struct TempPath {
+    path: PathBuf,
+    // what to remove
+    root: PathBuf,
+}
+
+impl TempPath {
+    /// The path `fishgill-<pid>-<name>`, which is not created.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("fishgill-{}-{}", std::process::id(), name));
+        TempPath {
+            path: path.clone(),
+            root: path,
+        }
+    }
+
+    /// The directory `fishgill-<pid>-<name>`, created empty.
+    pub fn dir(name: &str) -> Self {
+        let dir = TempPath::new(name);
+        let _ = std::fs::remove_dir_all(&dir.root);
+        std::fs::create_dir_all(&dir.root).unwrap();
+        dir
+    }
+
+    /// The file `file` in a new directory `fishgill-<pid>-<name>`, which
+    /// is removed when the path is dropped.
+    pub fn in_dir(name: &str, file: &str) -> Self {
+        let root = TempPath::dir(name).into_root();
+        TempPath {
+            path: root.join(file),
+            root,
+        }
+    }
+
+    // give up the path without removing it
+    fn into_root(mut self) -> PathBuf {
+        std::mem::take(&mut self.root)
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.root.as_os_str().is_empty() {
+            return;
+        }
+        let _ = if self.root.is_dir() {
+            std::fs::remove_dir_all(&self.root)
+        } else {
+            std::fs::remove_file(&self.root)
+        };
+    }
+}
@@ -0,0 +1,382 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use slog::{crit, info, warn};
+
+use crate::reader::ref_id;
+use crate::RadReader;
+
+// the number of problems we keep the messages of
+const MAX_ERRORS: usize = 100;
+
+/// The outcome of checking a RAD file with `check_rad`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Validation {
+    /// `num_chunks` as declared in the header
+    pub declared_chunks: u64,
+    /// the number of complete chunks in the file
+    pub found_chunks: u64,
+    pub num_reads: u64,
+    pub num_alignments: u64,
+    /// whether the file ends in the middle of a chunk
+    pub truncated: bool,
+    /// the byte offset of the end of the last complete chunk
+    pub end_pos: u64,
+    /// the byte offset of `num_chunks` in the header
+    pub num_chunks_offset: u64,
+    /// the number of problems with the contents of the chunks
+    pub num_errors: u64,
+    /// the messages of the first of these problems
+    pub errors: Vec<String>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.declared_chunks == self.found_chunks && !self.truncated && self.num_errors == 0
+    }
+
+    /// Like `is_valid`, but also accepts a chunk count of 0, with which
+    /// streamed files are read up to their end. An interrupted conversion
+    /// leaves the same count, so this is only for files known to be
+    /// streamed.
+    pub fn is_valid_stream(&self) -> bool {
+        (self.declared_chunks == self.found_chunks || self.declared_chunks == 0)
+            && !self.truncated
+            && self.num_errors == 0
+    }
+
+    /// Whether `repair_rad` can fix all the problems that were found, i.e.
+    /// only the chunk count is wrong or the last chunk is incomplete.
+    pub fn is_repairable(&self) -> bool {
+        !self.is_valid() && self.num_errors == 0
+    }
+
+    fn add_error(&mut self, msg: String) {
+        self.num_errors += 1;
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(msg);
+        }
+    }
+}
+
+/// Walk all chunks of a RAD file and check that they agree with the header
+/// and the tag descriptions. Only errors reading the header are returned
+/// as `Err`; all other problems are recorded in the `Validation`.
+pub fn check_rad<P: AsRef<Path>>(path: P) -> std::io::Result<Validation> {
+    let mut rdr = RadReader::from_path(path)?;
+    let mut v = Validation {
+        declared_chunks: rdr.header.num_chunks,
+        num_chunks_offset: rdr.header.num_chunks_offset(),
+        end_pos: rdr.position(),
+        ..Default::default()
+    };
+
+    let ref_count = rdr.header.ref_count;
+    // the number of bits the packed barcodes and UMIs may use
+    let bc_bits = rdr
+        .file_tag_value("cblen")
+        .and_then(|l| l.as_u64())
+        .map(|l| 2 * l);
+    let umi_bits = rdr
+        .file_tag_value("ulen")
+        .and_then(|l| l.as_u64())
+        .map(|l| 2 * l);
+    let fits = |value: u128, bits: Option<u64>| match bits {
+        Some(b) if b < 128 => value >> b == 0,
+        _ => true,
+    };
+
+    loop {
+        let idx = rdr.chunks_read();
+        let offset = rdr.position();
+        let raw = match rdr.next_raw_chunk() {
+            Ok(Some(raw)) => raw,
+            Ok(None) => break,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                v.truncated = true;
+                break;
+            }
+            Err(e) => {
+                // without a valid size we cannot find the next chunk
+                v.add_error(format!("chunk {} at byte {}: {}", idx, offset, e));
+                break;
+            }
+        };
+        v.found_chunks += 1;
+        v.end_pos = rdr.position();
+
+        let chunk = match rdr.decode_chunk(&raw) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                v.add_error(format!("chunk {} at byte {}: {}", idx, offset, e));
+                continue;
+            }
+        };
+        for (i, rec) in chunk.reads.iter().enumerate() {
+            v.num_reads += 1;
+            v.num_alignments += rec.tids.len() as u64;
+            if rec.tids.is_empty() {
+                v.add_error(format!("chunk {} record {}: read without alignments", idx, i));
+            }
+            if !fits(rec.bc, bc_bits) {
+                v.add_error(format!(
+                    "chunk {} record {}: barcode {} is wider than {} bits",
+                    idx,
+                    i,
+                    rec.bc,
+                    bc_bits.unwrap()
+                ));
+            }
+            if !fits(rec.umi as u128, umi_bits) {
+                v.add_error(format!(
+                    "chunk {} record {}: UMI {} is wider than {} bits",
+                    idx,
+                    i,
+                    rec.umi,
+                    umi_bits.unwrap()
+                ));
+            }
+            for t in rec.tids.iter() {
+                if ref_id(*t) as u64 >= ref_count {
+                    v.add_error(format!(
+                        "chunk {} record {}: reference id {} is not below the reference count {}",
+                        idx,
+                        i,
+                        ref_id(*t),
+                        ref_count
+                    ));
+                }
+            }
+        }
+    }
+    Ok(v)
+}
+
+/// Fix the chunk count in the header of a RAD file checked by `check_rad`,
+/// dropping an incomplete last chunk.
+pub fn repair_rad<P: AsRef<Path>>(path: P, v: &Validation) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    if v.truncated {
+        file.set_len(v.end_pos)?;
+    }
+    file.seek(SeekFrom::Start(v.num_chunks_offset))?;
+    file.write_all(&v.found_chunks.to_le_bytes())?;
+    file.flush()
+}
+
+/// Check a RAD file, logging the problems found, and optionally repair the
+/// chunk count, which also fills in the count of a streamed file. A count
+/// of 0 is only accepted if `streamed` is set. Returns whether the file is
+/// (now) valid.
+pub fn validate(rad_file: &str, repair: bool, streamed: bool, log: &slog::Logger) -> bool {
+    let v = match check_rad(rad_file) {
+        Ok(v) => v,
+        Err(e) => {
+            crit!(log, "couldn't read the header of {}: {}", rad_file, e);
+            return false;
+        }
+    };
+    info!(
+        log,
+        "{} chunks ({} declared), {} reads, {} alignments",
+        v.found_chunks,
+        v.declared_chunks,
+        v.num_reads,
+        v.num_alignments
+    );
+    for e in v.errors.iter() {
+        warn!(log, "{}", e);
+    }
+    if v.num_errors > v.errors.len() as u64 {
+        warn!(log, "... and {} more problems", v.num_errors - v.errors.len() as u64);
+    }
+    if v.truncated {
+        warn!(log, "the file ends in the middle of chunk {}", v.found_chunks);
+    }
+    if v.declared_chunks != v.found_chunks {
+        if v.declared_chunks == 0 && streamed {
+            info!(
+                log,
+                "num_chunks is 0, so the chunks are read up to the end of the file"
+            );
+        } else if v.declared_chunks == 0 {
+            warn!(
+                log,
+                "num_chunks is 0 but {} chunks were found; was the conversion interrupted?",
+                v.found_chunks
+            );
+        } else {
+            warn!(
+                log,
+                "num_chunks is {} but {} chunks were found",
+                v.declared_chunks,
+                v.found_chunks
+            );
+        }
+    }
+
+    let valid = if streamed {
+        v.is_valid_stream()
+    } else {
+        v.is_valid()
+    };
+    if !repair || !v.is_repairable() {
+        if valid {
+            info!(log, "{} is valid", rad_file);
+            return true;
+        }
+        if !repair {
+            crit!(log, "{} is not valid", rad_file);
+        } else {
+            crit!(log, "{} has problems that cannot be repaired", rad_file);
+        }
+        return false;
+    }
+    match repair_rad(rad_file, &v) {
+        Ok(()) => {
+            info!(log, "repaired {}: num_chunks set to {}", rad_file, v.found_chunks);
+            true
+        }
+        Err(e) => {
+            crit!(log, "couldn't repair {}: {}", rad_file, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempPath, TestRad};
+    use crate::RADType;
+    use std::io::Cursor;
+
+    // how `write_rad` ends the file
+    enum Ending {
+        Finish,
+        // streamed, with num_chunks left at 0
+        Stream,
+        // as if the writer was killed before patching num_chunks
+        Interrupt,
+    }
+
+    // write a RAD file with `n` one-read chunks
+    fn write_rad(name: &str, n: u64, ending: Ending) -> TempPath {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .lengths(RADType::U8, 3, RADType::U8, 2)
+            .writer(&mut out)
+            .chunk_records(1);
+        for i in 0..n {
+            writer.write_read(i as u128, i, &[0x80000001]).unwrap();
+        }
+        match ending {
+            Ending::Finish => {
+                writer.finish().unwrap();
+            }
+            Ending::Stream => {
+                writer.finish_stream().unwrap();
+            }
+            Ending::Interrupt => (),
+        }
+
+        let path = TempPath::new(&format!("validate-{}.rad", name));
+        std::fs::write(&path, out.into_inner()).unwrap();
+        path
+    }
+
+    #[test]
+    fn accepts_valid_file() {
+        let path = write_rad("valid", 3, Ending::Finish);
+        let v = check_rad(&path).unwrap();
+        assert!(v.is_valid());
+        assert_eq!(v.found_chunks, 3);
+        assert_eq!(v.num_reads, 3);
+        assert_eq!(v.end_pos, std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn repairs_unpatched_chunk_count() {
+        let path = write_rad("unpatched", 3, Ending::Interrupt);
+        let v = check_rad(&path).unwrap();
+        assert_eq!(v.declared_chunks, 0);
+        assert_eq!(v.found_chunks, 3);
+        assert!(v.is_repairable());
+
+        repair_rad(&path, &v).unwrap();
+        assert!(check_rad(&path).unwrap().is_valid());
+    }
+
+    #[test]
+    fn accepts_streamed_chunk_count_only_when_asked() {
+        let path = write_rad("streamed", 3, Ending::Stream);
+        let v = check_rad(&path).unwrap();
+        assert_eq!(v.declared_chunks, 0);
+        assert!(!v.is_valid());
+        assert!(v.is_valid_stream());
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        assert!(!validate(path.to_str().unwrap(), false, false, &log));
+        assert!(validate(path.to_str().unwrap(), false, true, &log));
+
+        // --repair fills in the count
+        assert!(validate(path.to_str().unwrap(), true, true, &log));
+        let v = check_rad(&path).unwrap();
+        assert!(v.is_valid());
+        assert_eq!(v.declared_chunks, 3);
+    }
+
+    #[test]
+    fn repairs_truncated_chunk() {
+        let path = write_rad("truncated", 3, Ending::Finish);
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let v = check_rad(&path).unwrap();
+        assert!(v.truncated);
+        assert_eq!(v.found_chunks, 2);
+
+        repair_rad(&path, &v).unwrap();
+        let v = check_rad(&path).unwrap();
+        assert!(v.is_valid());
+        assert_eq!(v.declared_chunks, 2);
+    }
+
+    #[test]
+    fn reports_bad_reference_ids_and_barcodes() {
+        let path = write_rad("badvalues", 2, Ending::Finish);
+        let mut bytes = std::fs::read(&path).unwrap();
+        // the last read: na, b, u and the reference id
+        let len = bytes.len();
+        bytes[len - 6] = 0xff;
+        bytes[len - 4..].copy_from_slice(&0x80000002u32.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        let v = check_rad(&path).unwrap();
+        assert_eq!(v.num_errors, 2);
+        assert!(!v.is_repairable());
+        assert!(v.errors[0].contains("barcode 255"));
+        assert!(v.errors[1].contains("reference id 2"));
+    }
+
+    #[test]
+    fn reports_corrupt_record_count() {
+        let path = write_rad("badnrec", 2, Ending::Finish);
+        let mut bytes = std::fs::read(&path).unwrap();
+        // the last chunk: nbytes, nrec and one 10-byte read
+        let len = bytes.len();
+        bytes[len - 14..len - 10].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        let v = check_rad(&path).unwrap();
+        assert_eq!(v.found_chunks, 2);
+        assert_eq!(v.num_errors, 1);
+        assert!(!v.is_repairable());
+        assert!(v.errors[0].contains("record 1 of 4294967295"));
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::{Read, Write};
+
+use serde_json::{json, Value};
+
+use crate::convert::u128_to_barcode_string;
+use crate::reader::{is_fw, ref_id, ReadRecord};
+use crate::{RadReader, TagSection, TagValue};
+
+/// Output formats of `view`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ViewFormat {
+    /// a commented header followed by one line per alignment
+    Tsv,
+    /// one JSON object for the header followed by one per read
+    Json,
+}
+
+// decode a packed barcode or UMI if its length is known
+fn seq_string(value: u128, len: Option<u64>) -> String {
+    match len {
+        Some(l) if l <= 64 => u128_to_barcode_string(value, l as usize),
+        _ => value.to_string(),
+    }
+}
+
+fn json_value(v: &TagValue) -> Value {
+    match v {
+        TagValue::BOOL(v) => json!(v),
+        TagValue::U8(v) => json!(v),
+        TagValue::U16(v) => json!(v),
+        TagValue::U32(v) => json!(v),
+        TagValue::U64(v) => json!(v),
+        TagValue::F32(v) => json!(v),
+        TagValue::F64(v) => json!(v),
+        // JSON numbers are not meant to hold 128 bits
+        TagValue::U128(v) => json!(v.to_string()),
+    }
+}
+
+fn json_tags(tags: &TagSection) -> Value {
+    Value::Array(
+        tags.tags
+            .iter()
+            .map(|t| json!({"name": t.name, "type": format!("{:?}", t.typeid)}))
+            .collect(),
+    )
+}
+
+/// The names of the alignment-level tags other than the reference id.
+fn extra_aln_tags(tags: &TagSection) -> Vec<&str> {
+    tags.tags
+        .iter()
+        .map(|t| t.name.as_str())
+        .filter(|n| *n != "compressed_ori_refid")
+        .collect()
+}
+
+/// Print the header, tag descriptions and reads of a RAD file in `format`,
+/// stopping after `head` reads if it is given.
+pub fn view<R: Read, W: Write>(
+    mut rdr: RadReader<R>,
+    format: ViewFormat,
+    head: Option<u64>,
+    out: &mut W,
+) -> std::io::Result<()> {
+    let bclen = rdr.file_tag_value("cblen").and_then(|v| v.as_u64());
+    let umilen = rdr.file_tag_value("ulen").and_then(|v| v.as_u64());
+    let extra_names: Vec<String> = extra_aln_tags(&rdr.aln_tags)
+        .into_iter()
+        .map(|n| n.to_string())
+        .collect();
+    let ref_names = rdr.header.ref_names.clone();
+    let ref_name = |tid: u32| -> &str {
+        ref_names
+            .get(ref_id(tid) as usize)
+            .map_or("*", |n| n.as_str())
+    };
+
+    match format {
+        ViewFormat::Tsv => {
+            writeln!(out, "#is_paired\t{}", rdr.header.is_paired)?;
+            writeln!(out, "#ref_count\t{}", rdr.header.ref_count)?;
+            writeln!(out, "#num_chunks\t{}", rdr.header.num_chunks)?;
+            for (t, v) in rdr.file_tags.tags.iter().zip(rdr.file_tag_values.iter()) {
+                writeln!(out, "#file_tag\t{}\t{:?}\t{}", t.name, t.typeid, v)?;
+            }
+            for t in rdr.read_tags.tags.iter() {
+                writeln!(out, "#read_tag\t{}\t{:?}", t.name, t.typeid)?;
+            }
+            for t in rdr.aln_tags.tags.iter() {
+                writeln!(out, "#aln_tag\t{}\t{:?}", t.name, t.typeid)?;
+            }
+            write!(out, "read\tbc\tumi\tna\tref\tori")?;
+            for n in extra_names.iter() {
+                write!(out, "\t{}", n)?;
+            }
+            writeln!(out)?;
+        }
+        ViewFormat::Json => {
+            let file_tags: serde_json::Map<String, Value> = rdr
+                .file_tags
+                .tags
+                .iter()
+                .zip(rdr.file_tag_values.iter())
+                .map(|(t, v)| (t.name.clone(), json_value(v)))
+                .collect();
+            let hdr = json!({
+                "is_paired": rdr.header.is_paired,
+                "ref_count": rdr.header.ref_count,
+                "ref_names": rdr.header.ref_names,
+                "num_chunks": rdr.header.num_chunks,
+                "file_tags": json_tags(&rdr.file_tags),
+                "read_tags": json_tags(&rdr.read_tags),
+                "aln_tags": json_tags(&rdr.aln_tags),
+                "file_tag_values": file_tags,
+            });
+            writeln!(out, "{}", hdr)?;
+        }
+    }
+
+    let mut num_reads = 0u64;
+    let write_read = |out: &mut W, idx: u64, rec: &ReadRecord| -> std::io::Result<()> {
+        let bc = seq_string(rec.bc, bclen);
+        let umi = seq_string(rec.umi as u128, umilen);
+        let num_extra = extra_names.len();
+        match format {
+            ViewFormat::Tsv => {
+                for (i, t) in rec.tids.iter().enumerate() {
+                    write!(
+                        out,
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        idx,
+                        bc,
+                        umi,
+                        rec.tids.len(),
+                        ref_name(*t),
+                        if is_fw(*t) { '+' } else { '-' }
+                    )?;
+                    for v in rec.aln_tags[i * num_extra..(i + 1) * num_extra].iter() {
+                        write!(out, "\t{}", v)?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+            ViewFormat::Json => {
+                let alns: Vec<Value> = rec
+                    .tids
+                    .iter()
+                    .enumerate()
+                    .map(|(i, t)| {
+                        let mut aln = serde_json::Map::new();
+                        aln.insert("ref".to_string(), json!(ref_name(*t)));
+                        aln.insert("ori".to_string(), json!(if is_fw(*t) { "+" } else { "-" }));
+                        for (n, v) in extra_names
+                            .iter()
+                            .zip(rec.aln_tags[i * num_extra..(i + 1) * num_extra].iter())
+                        {
+                            aln.insert(n.clone(), json_value(v));
+                        }
+                        Value::Object(aln)
+                    })
+                    .collect();
+                let read = json!({
+                    "read": idx,
+                    "bc": bc,
+                    "umi": umi,
+                    "na": rec.tids.len(),
+                    "alignments": alns,
+                });
+                writeln!(out, "{}", read)?;
+            }
+        }
+        Ok(())
+    };
+
+    'chunks: while let Some(chunk) = rdr.next_chunk()? {
+        for rec in chunk.reads.iter() {
+            if matches!(head, Some(h) if num_reads >= h) {
+                break 'chunks;
+            }
+            write_read(out, num_reads, rec)?;
+            num_reads += 1;
+        }
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::cb_string_to_u64;
+    use crate::test_util::TestRad;
+    use crate::RADType;
+    use std::io::Cursor;
+
+    fn example_rad() -> Cursor<Vec<u8>> {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = TestRad::default()
+            .lengths(RADType::U16, 6, RADType::U8, 3)
+            .aln_tag("pos", RADType::U32)
+            .writer(&mut out);
+        let bc = cb_string_to_u64(b"ACGTTG").unwrap() as u128;
+        let umi = cb_string_to_u64(b"CAT").unwrap();
+        writer
+            .write_read_with_tags(bc, umi, &[0x80000000, 1], &[TagValue::U32(5), TagValue::U32(7)])
+            .unwrap();
+        writer
+            .write_read_with_tags(bc, umi, &[1], &[TagValue::U32(9)])
+            .unwrap();
+        writer.finish().unwrap();
+        out.set_position(0);
+        out
+    }
+
+    #[test]
+    fn views_tsv() {
+        let mut out = vec![];
+        view(RadReader::new(example_rad()).unwrap(), ViewFormat::Tsv, Some(1), &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert!(text.contains("#file_tag\tcblen\tU16\t6\n"));
+        assert_eq!(
+            lines,
+            vec![
+                "read\tbc\tumi\tna\tref\tori\tpos",
+                "0\tACGTTG\tCAT\t2\ttxp1\t+\t5",
+                "0\tACGTTG\tCAT\t2\ttxp2\t-\t7",
+            ]
+        );
+    }
+
+    #[test]
+    fn views_json_lines() {
+        let mut out = vec![];
+        view(RadReader::new(example_rad()).unwrap(), ViewFormat::Json, None, &mut out).unwrap();
+        let lines: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["ref_names"], json!(["txp1", "txp2"]));
+        assert_eq!(lines[0]["file_tag_values"]["ulen"], json!(3));
+        assert_eq!(lines[1]["bc"], json!("ACGTTG"));
+        assert_eq!(
+            lines[1]["alignments"][1],
+            json!({"ref": "txp2", "ori": "-", "pos": 7})
+        );
+        assert_eq!(lines[2]["read"], json!(1));
+    }
+}
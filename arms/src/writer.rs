@@ -0,0 +1,528 @@
+use std::convert::TryFrom;
+use std::io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::codec::Codec;
+use crate::index::{ChunkIndexEntry, RadIndex};
+use crate::reader::{RawChunk, ReadRecord};
+use crate::{RADHeader, RADIntID, RADType, RadReader, TagSection, TagValue};
+
+/// Streaming writer for RAD files.
+///
+/// The header, tag sections and file-level tag values are written when the
+/// writer is created. Reads are then buffered into chunks, which are written
+/// out once they hold `chunk_records` reads or `chunk_bytes` bytes, and the
+/// number of chunks in the header is patched by `finish`. Outputs that
+/// cannot seek, such as pipes, are finished with `finish_stream` instead.
+/// The writer keeps an index of the chunks it has written, see
+/// `finish_with_index` and `index_barcodes`.
+///
+/// The chunks are compressed if the file-level tags include a `codec`
+/// (see `codec::Codec`).
+pub struct RadWriter<W: Write> {
+    owriter: W,
+    bc_type: RADIntID,
+    umi_type: RADIntID,
+    // whether the barcode is the first of the two read-level tags
+    bc_first: bool,
+    refid_idx: usize,
+    // the types of the alignment-level tags other than the reference id
+    extra_types: Vec<RADType>,
+    codec: Codec,
+    data: Cursor<Vec<u8>>,
+    local_nrec: u32,
+    num_chunks: u64,
+    num_chunks_offset: u64,
+    max_records: u32,
+    max_bytes: usize,
+    // the byte offset of the next chunk
+    pos: u64,
+    index: RadIndex,
+    // whether `write_raw_chunk` finds the barcode range of the chunks
+    index_barcodes: bool,
+    chunk_min_bc: u128,
+    chunk_max_bc: u128,
+}
+
+fn int_tag_type(tags: &TagSection, name: &str) -> std::io::Result<RADIntID> {
+    tags.position(name)
+        .and_then(|idx| tags.tags[idx].typeid.int_id())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("no integer tag {:?} in tag section", name),
+            )
+        })
+}
+
+// the nbytes field of a chunk of `len` bytes, which must fit into 32 bits
+fn chunk_size(len: usize) -> std::io::Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("a chunk of {} bytes is too large for a RAD file", len),
+        )
+    })
+}
+
+impl<W: Write> RadWriter<W> {
+    /// Write the header and tag sections to `owriter`, which is expected
+    /// to be positioned at the start of the output.
+    ///
+    /// The read-level tags must be the barcode `b` and UMI `u`, and the
+    /// alignment-level tags must include the u32 `compressed_ori_refid`.
+    pub fn new(
+        mut owriter: W,
+        header: &RADHeader,
+        file_tags: &TagSection,
+        file_tag_values: &[TagValue],
+        read_tags: &TagSection,
+        aln_tags: &TagSection,
+    ) -> std::io::Result<Self> {
+        let bc_type = int_tag_type(read_tags, "b")?;
+        let codec = Codec::from_file_tags(file_tags, file_tag_values)?;
+        let umi_type = int_tag_type(read_tags, "u")?;
+        if read_tags.tags.len() != 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "only the b and u read-level tags are supported",
+            ));
+        }
+        if int_tag_type(aln_tags, "compressed_ori_refid")? != RADIntID::U32 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the compressed_ori_refid alignment-level tag must be a u32",
+            ));
+        }
+
+        header.write_to(&mut owriter)?;
+        file_tags.write_to(&mut owriter)?;
+        read_tags.write_to(&mut owriter)?;
+        aln_tags.write_to(&mut owriter)?;
+        file_tags.write_values(file_tag_values, &mut owriter)?;
+
+        let mut writer = RadWriter {
+            owriter,
+            bc_type,
+            umi_type,
+            bc_first: read_tags.position("b") == Some(0),
+            refid_idx: aln_tags.position("compressed_ori_refid").unwrap(),
+            extra_types: aln_tags
+                .tags
+                .iter()
+                .filter(|t| t.name != "compressed_ori_refid")
+                .map(|t| t.typeid)
+                .collect(),
+            codec,
+            data: Cursor::new(Vec::new()),
+            local_nrec: 0,
+            num_chunks: 0,
+            num_chunks_offset: header.num_chunks_offset(),
+            max_records: 10000,
+            max_bytes: usize::MAX,
+            pos: (header.get_size()
+                + file_tags.get_size()
+                + read_tags.get_size()
+                + aln_tags.get_size()
+                + file_tags.values_size()) as u64,
+            index: RadIndex::default(),
+            index_barcodes: true,
+            chunk_min_bc: u128::MAX,
+            chunk_max_bc: 0,
+        };
+        writer.reset_chunk();
+        Ok(writer)
+    }
+
+    /// Write the header and tag sections of the file read by `rdr`, with
+    /// the same references and file-level tag values but no chunks yet.
+    pub fn with_header_of<R: Read>(owriter: W, rdr: &RadReader<R>) -> std::io::Result<Self> {
+        let header = RADHeader {
+            num_chunks: 0,
+            ..rdr.header.clone()
+        };
+        RadWriter::new(
+            owriter,
+            &header,
+            &rdr.file_tags,
+            &rdr.file_tag_values,
+            &rdr.read_tags,
+            &rdr.aln_tags,
+        )
+    }
+
+    /// Flush a chunk once it holds `n` reads (10000 by default).
+    pub fn chunk_records(mut self, n: u32) -> Self {
+        self.max_records = n.max(1);
+        self
+    }
+
+    /// Flush a chunk once it holds at least `n` bytes, in addition to the
+    /// limit on the number of reads.
+    pub fn chunk_bytes(mut self, n: usize) -> Self {
+        self.max_bytes = n;
+        self
+    }
+
+    /// Whether to find the smallest and largest barcode of the chunks
+    /// copied by `write_raw_chunk` for the index (the default). This reads
+    /// the records of each chunk, decompressing them if needed, so writers
+    /// whose index is not used can turn it off; the index then gives these
+    /// chunks the full barcode range.
+    pub fn index_barcodes(mut self, yes: bool) -> Self {
+        self.index_barcodes = yes;
+        self
+    }
+
+    /// The number of chunks written so far.
+    pub fn num_chunks(&self) -> u64 {
+        self.num_chunks
+    }
+
+    /// The index of the chunks written so far.
+    pub fn index(&self) -> &RadIndex {
+        &self.index
+    }
+
+    fn add_index_entry(&mut self, nbytes: u32, nrec: u32, min_bc: u128, max_bc: u128) {
+        self.index.chunks.push(ChunkIndexEntry {
+            offset: self.pos,
+            nbytes,
+            nrec,
+            min_bc,
+            max_bc,
+        });
+        self.pos += nbytes as u64;
+        self.num_chunks += 1;
+    }
+
+    fn reset_chunk(&mut self) {
+        let capacity = (self.max_records as usize).saturating_mul(24).min(1 << 24);
+        self.data = Cursor::new(Vec::with_capacity(capacity));
+        self.local_nrec = 0;
+        self.chunk_min_bc = u128::MAX;
+        self.chunk_max_bc = 0;
+        // placeholders for nbytes and nrec
+        self.data.write_all(&0u32.to_le_bytes()).unwrap();
+        self.data.write_all(&0u32.to_le_bytes()).unwrap();
+    }
+
+    /// Write out the current chunk, if it holds any reads.
+    pub fn flush_chunk(&mut self) -> std::io::Result<()> {
+        if self.local_nrec == 0 {
+            return Ok(());
+        }
+        let nrec = self.local_nrec;
+        let nbytes = if self.codec == Codec::None {
+            let nbytes = chunk_size(self.data.get_ref().len())?;
+            self.data.set_position(0);
+            self.data.write_all(&nbytes.to_le_bytes())?;
+            self.data.write_all(&nrec.to_le_bytes())?;
+            self.owriter.write_all(self.data.get_ref())?;
+            nbytes
+        } else {
+            let body = self.codec.compress(&self.data.get_ref()[8..])?;
+            let nbytes = chunk_size(8 + body.len())?;
+            self.owriter.write_all(&nbytes.to_le_bytes())?;
+            self.owriter.write_all(&nrec.to_le_bytes())?;
+            self.owriter.write_all(&body)?;
+            nbytes
+        };
+        self.add_index_entry(nbytes, nrec, self.chunk_min_bc, self.chunk_max_bc);
+        self.reset_chunk();
+        Ok(())
+    }
+
+    /// Copy a chunk read from a file with the same tag sections and codec
+    /// as it is, after writing out the current chunk.
+    pub fn write_raw_chunk(&mut self, raw: &RawChunk) -> std::io::Result<()> {
+        self.flush_chunk()?;
+        let (min_bc, max_bc) = if self.index_barcodes {
+            self.raw_barcode_range(raw)?
+        } else {
+            (0, u128::MAX)
+        };
+        self.owriter.write_all(&raw.nbytes.to_le_bytes())?;
+        self.owriter.write_all(&raw.nrec.to_le_bytes())?;
+        self.owriter.write_all(&raw.data)?;
+        self.add_index_entry(raw.nbytes, raw.nrec, min_bc, max_bc);
+        Ok(())
+    }
+
+    // the smallest and largest barcode in a chunk, skipping over the
+    // records without decoding them
+    fn raw_barcode_range(&self, raw: &RawChunk) -> std::io::Result<(u128, u128)> {
+        let bc_size = self.bc_type.bytes_for_type();
+        let umi_size = self.umi_type.bytes_for_type();
+        let aln_size = 4 + self
+            .extra_types
+            .iter()
+            .map(|t| t.bytes_for_type())
+            .sum::<usize>();
+        let bc_offset = if self.bc_first { 4 } else { 4 + umi_size };
+        let overrun = || Error::new(ErrorKind::InvalidData, "record overruns the chunk");
+
+        let (mut min_bc, mut max_bc) = (u128::MAX, 0);
+        let records = self.codec.decompress(&raw.data)?;
+        let mut data = &records[..];
+        for _ in 0..raw.nrec {
+            if data.len() < 4 + bc_size + umi_size {
+                return Err(overrun());
+            }
+            let na = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            let bc = self.bc_type.read_u128_from(&mut &data[bc_offset..])?;
+            min_bc = min_bc.min(bc);
+            max_bc = max_bc.max(bc);
+            let rec_size = 4 + bc_size + umi_size + na * aln_size;
+            data = data.get(rec_size..).ok_or_else(overrun)?;
+        }
+        Ok((min_bc, max_bc))
+    }
+
+    /// Add a read with barcode `bc`, UMI `umi` and the compressed
+    /// orientation and reference ids of its alignments. This requires
+    /// `compressed_ori_refid` to be the only alignment-level tag.
+    pub fn write_read(&mut self, bc: u128, umi: u64, alns: &[u32]) -> std::io::Result<()> {
+        self.write_read_with_tags(bc, umi, alns, &[])
+    }
+
+    /// Add a read whose alignments carry further alignment-level tags.
+    /// `aln_tags` holds the values of the tags other than the reference id,
+    /// in header order, for one alignment after the other.
+    pub fn write_read_with_tags(
+        &mut self,
+        bc: u128,
+        umi: u64,
+        alns: &[u32],
+        aln_tags: &[TagValue],
+    ) -> std::io::Result<()> {
+        if alns.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "trying to write a read without alignments",
+            ));
+        }
+        let num_extra = self.extra_types.len();
+        if aln_tags.len() != alns.len() * num_extra {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "expected {} alignment-level tag values for {} alignments, got {}",
+                    alns.len() * num_extra,
+                    alns.len(),
+                    aln_tags.len()
+                ),
+            ));
+        }
+        // check the types up front so that no partial record is written
+        if let Some((idx, v)) = aln_tags
+            .iter()
+            .enumerate()
+            .find(|(idx, v)| v.typeid() != self.extra_types[idx % num_extra])
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "value {:?} does not match the type {:?} of its tag",
+                    v,
+                    self.extra_types[idx % num_extra]
+                ),
+            ));
+        }
+        let na = alns.len() as u32;
+        self.chunk_min_bc = self.chunk_min_bc.min(bc);
+        self.chunk_max_bc = self.chunk_max_bc.max(bc);
+        self.data.write_all(&na.to_le_bytes())?;
+        if self.bc_first {
+            self.bc_type.write_to(bc, &mut self.data)?;
+            self.umi_type.write_to(umi, &mut self.data)?;
+        } else {
+            self.umi_type.write_to(umi, &mut self.data)?;
+            self.bc_type.write_to(bc, &mut self.data)?;
+        }
+        let mut values = aln_tags.iter();
+        for t in alns.iter() {
+            for idx in 0..=num_extra {
+                if idx == self.refid_idx {
+                    self.data.write_all(&t.to_le_bytes())?;
+                } else {
+                    values.next().unwrap().write_to(&mut self.data)?;
+                }
+            }
+        }
+        self.local_nrec += 1;
+
+        if self.local_nrec >= self.max_records || self.data.get_ref().len() >= self.max_bytes {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Add a read obtained from a `RadReader`.
+    pub fn write_record(&mut self, rec: &ReadRecord) -> std::io::Result<()> {
+        self.write_read_with_tags(rec.bc, rec.umi, &rec.tids, &rec.aln_tags)
+    }
+
+    /// Write out the last chunk without going back to the header, which
+    /// keeps `num_chunks = 0`; readers then read the chunks up to the end
+    /// of the file. Returns the index of the chunks written.
+    pub fn finish_stream(mut self) -> std::io::Result<RadIndex> {
+        self.flush_chunk()?;
+        self.owriter.flush()?;
+        Ok(self.index)
+    }
+}
+
+impl<W: Write + Seek> RadWriter<W> {
+    /// Write out the last chunk and patch the number of chunks in the
+    /// header. Returns the number of chunks written.
+    pub fn finish(self) -> std::io::Result<u64> {
+        self.finish_with_index().map(|index| index.num_chunks())
+    }
+
+    /// Like `finish`, but returns the index of the chunks written.
+    pub fn finish_with_index(mut self) -> std::io::Result<RadIndex> {
+        self.flush_chunk()?;
+        self.owriter.flush()?;
+        self.owriter.seek(SeekFrom::Start(self.num_chunks_offset))?;
+        self.owriter.write_all(&self.num_chunks.to_le_bytes())?;
+        self.owriter.flush()?;
+        Ok(self.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::Chunk;
+    use crate::test_util::TestRad;
+    use crate::RadReader;
+
+    fn new_writer(out: &mut Cursor<Vec<u8>>) -> RadWriter<&mut Cursor<Vec<u8>>> {
+        new_writer_with_types(out, RADType::U32, RADType::U32)
+    }
+
+    fn new_writer_with_types(
+        out: &mut Cursor<Vec<u8>>,
+        bc_type: RADType,
+        umi_type: RADType,
+    ) -> RadWriter<&mut Cursor<Vec<u8>>> {
+        TestRad::default()
+            .lengths(bc_type, 16, umi_type, 10)
+            .writer(out)
+    }
+
+    fn read_back(mut out: Cursor<Vec<u8>>) -> Vec<Chunk> {
+        out.set_position(0);
+        RadReader::new(out)
+            .unwrap()
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn chunks_by_record_count() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = new_writer(&mut out).chunk_records(2);
+        for i in 0..5u64 {
+            writer.write_read(i as u128, i + 1, &[0x80000000, 1]).unwrap();
+        }
+        assert_eq!(writer.num_chunks(), 2);
+        assert_eq!(writer.finish().unwrap(), 3);
+
+        let chunks = read_back(out);
+        assert_eq!(chunks.iter().map(|c| c.nrec).collect::<Vec<_>>(), vec![2, 2, 1]);
+        assert_eq!(chunks[2].reads[0].bc, 4);
+        assert_eq!(chunks[2].reads[0].umi, 5);
+        assert_eq!(chunks[2].reads[0].tids, vec![0x80000000, 1]);
+    }
+
+    #[test]
+    fn chunks_by_byte_budget() {
+        let mut out = Cursor::new(Vec::new());
+        // each read takes 4 + 4 + 4 + 4 bytes
+        let mut writer = new_writer(&mut out).chunk_bytes(8 + 3 * 16);
+        for i in 0..7u64 {
+            writer.write_read(i as u128, i, &[1]).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let chunks = read_back(out);
+        assert_eq!(chunks.iter().map(|c| c.nrec).collect::<Vec<_>>(), vec![3, 3, 1]);
+    }
+
+    #[test]
+    fn writes_declared_widths() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = new_writer_with_types(&mut out, RADType::U64, RADType::U8);
+        // a 32 base barcode and a 4 base UMI
+        let bc = crate::convert::cb_string_to_u64(b"TTTTACGTACGTACGTACGTACGTACGTACGT").unwrap();
+        let umi = crate::convert::cb_string_to_u64(b"TGCA").unwrap();
+        assert!(bc > u32::MAX as u64);
+        let bc = bc as u128;
+        writer.write_read(bc, umi, &[1]).unwrap();
+        writer.finish().unwrap();
+
+        let chunks = read_back(out);
+        assert_eq!(chunks[0].nbytes, 8 + 4 + 8 + 1 + 4);
+        assert_eq!(chunks[0].reads[0].bc, bc);
+        assert_eq!(chunks[0].reads[0].umi, umi);
+    }
+
+    #[test]
+    fn writes_alignment_level_tags() {
+        let mut rad = TestRad::default()
+            .refs(&["txp1"])
+            .aln_tag("mate_ori", RADType::U8);
+        rad.header.is_paired = 1;
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = rad.writer(&mut out);
+        assert!(writer.write_read(1, 2, &[0]).is_err());
+        assert!(writer
+            .write_read_with_tags(1, 2, &[0], &[TagValue::U32(5)])
+            .is_err());
+        writer
+            .write_read_with_tags(1, 2, &[0, 0x80000000], &[TagValue::U8(5), TagValue::U8(15)])
+            .unwrap();
+        writer.finish().unwrap();
+
+        let chunks = read_back(out);
+        assert_eq!(chunks[0].nrec, 1);
+        assert_eq!(chunks[0].reads[0].tids, vec![0, 0x80000000]);
+        assert_eq!(chunks[0].reads[0].aln_tags, vec![TagValue::U8(5), TagValue::U8(15)]);
+    }
+
+    #[test]
+    fn rejects_chunks_beyond_32_bits() {
+        assert_eq!(chunk_size(u32::MAX as usize).unwrap(), u32::MAX);
+        let e = chunk_size(u32::MAX as usize + 1).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_empty_alignment_list() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = new_writer(&mut out);
+        assert!(writer.write_read(1, 2, &[]).is_err());
+    }
+
+    #[test]
+    fn copies_raw_chunks_without_reading_them() {
+        // a record too short to hold a barcode
+        let raw = RawChunk {
+            nbytes: 12,
+            nrec: 1,
+            data: vec![1, 0, 0, 0],
+        };
+        let mut out = Cursor::new(Vec::new());
+        assert!(new_writer(&mut out).write_raw_chunk(&raw).is_err());
+
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = new_writer(&mut out).index_barcodes(false);
+        writer.write_raw_chunk(&raw).unwrap();
+        let index = writer.finish_with_index().unwrap();
+        assert_eq!(
+            (index.chunks[0].min_bc, index.chunks[0].max_bc),
+            (0, u128::MAX)
+        );
+    }
+}
@@ -5,9 +5,8 @@ extern crate num_cpus;
 extern crate slog;
 extern crate slog_term;
 
-use clap::{crate_authors, crate_version, App, Arg};
-use slog::{o, Drain};
-use arms;
+use clap::{crate_authors, crate_version, App, AppSettings, Arg};
+use slog::{crit, info, o, Drain};
 
 
 
@@ -30,10 +29,89 @@ fn main() {
             Arg::from("-t, --threads 'number of threads to use for processing'")
                 .default_value(&max_num_threads),
         )
-        .arg(Arg::from("-o, --output=<rad-file> 'output RAD file'")
-    );
+        .arg(Arg::from("-o, --output=<rad-file> 'output RAD file, - for stdout'"))
+        .arg(Arg::from("--paired 'write paired-end RAD, with one alignment per mate pair'"))
+        .arg(
+            Arg::from("--mate-pos 'record the positions of both mates (with --paired)'")
+                .requires("paired"),
+        )
+        .arg(Arg::from("--pos 'record the 0-based position of each alignment'"))
+        .arg(Arg::from("--frag-len 'record the fragment length of each alignment'"))
+        .arg(Arg::from(
+            "--dist-3p 'record the distance of each alignment to the 3\' end of its reference'",
+        ))
+        .arg(Arg::from(
+            "--index 'also write an index of the chunks to <rad-file>.idx'",
+        ))
+        .arg(Arg::from("--compress 'compress the records of each chunk with zlib'"))
+        .arg(
+            Arg::from(
+                "--ambiguous-bases=<policy> 'skip reads with IUPAC codes other than a single N in the barcode or UMI (reject), or pack them as A, C or G (map)'",
+            )
+            .possible_values(&["reject", "map"])
+            .default_value("reject"),
+        )
+        .arg(
+            Arg::from(
+                "--cb-tag=<tags> 'comma-separated tags holding the cell barcode, in order of preference'",
+            )
+            .default_value("CB"),
+        )
+        .arg(
+            Arg::from(
+                "--umi-tag=<tags> 'comma-separated tags holding the UMI, in order of preference, e.g. UB,UR'",
+            )
+            .default_value("UR"),
+        )
+        .arg(
+            Arg::from(
+                "--n-bases=<policy> 'skip reads with Ns in the barcode or UMI (drop), replace a single N by A (replace), or resolve the Ns of barcodes on the whitelist (expand)'",
+            )
+            .possible_values(&["drop", "replace", "expand"])
+            .default_value("replace"),
+        )
+        .arg(Arg::from(
+            "--whitelist=[file] 'barcodes to resolve Ns against with --n-bases expand, one per line'",
+        ))
+        .arg(
+            Arg::from(
+                "-f, --require-flags=<mask> 'only convert records with all of these SAM flags, e.g. 0x2'",
+            )
+            .default_value("0"),
+        )
+        .arg(
+            Arg::from(
+                "-F, --exclude-flags=<mask> 'skip records with any of these SAM flags, e.g. 0x904'",
+            )
+            .default_value("0x4"),
+        )
+        .arg(Arg::from(
+            "--dedup 'write each reference and strand once per read, counting a mate pair once'",
+        ))
+        .arg(Arg::from(
+            "--sort-tids 'sort the alignments of each read by reference'",
+        ))
+        .arg(Arg::from(
+            "--report=[json-file] 'write a JSON report of the conversion to this file'",
+        ))
+        .arg(Arg::from(
+            "--alevin-fry 'write an alevin-fry mapping directory: -o names the directory, which gets map.rad and its metadata'",
+        ));
 
 
+    let rad2bam_app = App::new("rad2bam")
+        .about("Convert a RAD file back to a BAM file, one record per alignment")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-i, --input=<rad-file> 'input RAD file'"))
+        .arg(
+            Arg::from("-t, --threads 'number of threads to use for compression'")
+                .default_value(&max_num_threads),
+        )
+        .arg(Arg::from(
+            "-o, --output=<bam-file> 'output BAM file (SAM if it ends in .sam)'",
+        ));
+
     let filter_app = App::new("filter")
     .about("remove alignments outside terminal kilobase")
     .version(version)
@@ -47,12 +125,127 @@ fn main() {
             .default_value(&max_num_threads),
     );
 
+    let view_app = App::new("view")
+        .about("Print the header and reads of a RAD file")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-i, --input=<rad-file> 'input RAD file, - for stdin'"))
+        .arg(
+            Arg::from("-f, --format=<format> 'output format'")
+                .possible_values(&["tsv", "json"])
+                .default_value("tsv"),
+        )
+        .arg(Arg::from("-n, --head=[N] 'only print the first N reads'"));
+
+    let validate_app = App::new("validate")
+        .about("Check the chunk count and the records of a RAD file")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-i, --input=<rad-file> 'input RAD file'"))
+        .arg(Arg::from(
+            "--repair 'fix the chunk count in the header, dropping an incomplete last chunk'",
+        ))
+        .arg(Arg::from(
+            "--streamed 'accept a chunk count of 0, as written by convert -o -'",
+        ));
+
+    let stats_app = App::new("stats")
+        .about("Summarize the reads and alignments in a RAD file")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-i, --input=<rad-file> 'input RAD file, - for stdin'"))
+        .arg(
+            Arg::from("-f, --format=<format> 'output format'")
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::from("--top=<N> 'number of references with the most hits to list'")
+                .default_value("10"),
+        );
+
+    let merge_app = App::new("merge")
+        .about("Concatenate the chunks of several RAD files")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-o, --output=<rad-file> 'output RAD file'"))
+        .arg(Arg::from(
+            "--remap 'rewrite the reference ids of inputs whose reference lists differ'",
+        ))
+        .arg(Arg::from("<input>... 'input RAD files'"));
+
+    let subset_app = App::new("subset")
+        .about("Keep the reads of a list of cell barcodes")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-i, --input=<rad-file> 'input RAD file, - for stdin'"))
+        .arg(Arg::from("-o, --output=<rad-file> 'output RAD file'"))
+        .arg(Arg::from(
+            "-b, --barcodes=<barcode-file> 'file with one cell barcode per line'",
+        ));
+
+    let split_app = App::new("split")
+        .about("Partition the reads of a RAD file by barcode hash")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-i, --input=<rad-file> 'input RAD file, - for stdin'"))
+        .arg(Arg::from(
+            "-o, --output=<prefix> 'prefix of the output RAD files, written as <prefix>.<part>.rad'",
+        ))
+        .arg(Arg::from("-n, --parts=<N> 'number of parts'"));
+
+    let index_app = App::new("index")
+        .about("Write an index of the chunks of a RAD file to <rad-file>.idx")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-i, --input=<rad-file> 'input RAD file'"));
+
+    let collate_app = App::new("collate")
+        .about("Group the reads of a RAD file by cell barcode, one chunk per cell")
+        .version(version)
+        .author(crate_authors)
+        .arg(Arg::from("-i, --input=<rad-file> 'input RAD file'"))
+        .arg(Arg::from("-o, --output=<rad-file> 'output RAD file'"))
+        .arg(Arg::from(
+            "-p, --permit=[barcode-file] 'only keep the cells listed in this file, one per line'",
+        ))
+        .arg(Arg::from(
+            "-c, --corrections=[map-file] 'file with a barcode and its correction on each line'",
+        ))
+        .arg(
+            Arg::from("--max-records=<N> 'number of reads to hold in memory at once'")
+                .default_value("10000000"),
+        )
+        .arg(
+            Arg::from("--cells-per-chunk=<N> 'number of cells to write to each chunk'")
+                .default_value("1"),
+        )
+        .arg(Arg::from(
+            "--tmp-dir=[dir] 'directory for temporary files (default: that of the output)'",
+        ));
+
+    let rad_app = App::new("rad")
+        .about("Inspect and manipulate RAD files")
+        .version(version)
+        .author(crate_authors)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(view_app)
+        .subcommand(validate_app)
+        .subcommand(stats_app)
+        .subcommand(merge_app)
+        .subcommand(subset_app)
+        .subcommand(split_app)
+        .subcommand(index_app)
+        .subcommand(collate_app);
+
     let opts = App::new("fishgill")
     .version(version)
     .author(crate_authors)
     .about("Cook BAM for alevin-fry from the command line")
     .subcommand(filter_app)
     .subcommand(convert_app)
+    .subcommand(rad2bam_app)
+    .subcommand(rad_app)
     .get_matches();
 
     
@@ -68,14 +261,29 @@ fn main() {
 
     let log = slog::Logger::root(drain, o!());
 
-    if let Some(ref t) = opts.subcommand_matches("convert") {
-        let input_file: String = t.value_of_t("bam").unwrap();
-        let rad_file: String = t.value_of_t("output").unwrap();
+    if let Some(t) = opts.subcommand_matches("convert") {
+        if let Err(msg) = convert(t, &log) {
+            crit!(log, "{}", msg);
+            drop(log);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(t) = opts.subcommand_matches("rad2bam") {
+        let rad_file: String = t.value_of_t("input").unwrap();
+        let bam_file: String = t.value_of_t("output").unwrap();
         let num_threads: u32 = t.value_of_t("threads").unwrap();
-        arms::convert::bam2rad(input_file, rad_file, num_threads, &log)
+        match arms::rad2bam::rad2bam(&rad_file, &bam_file, num_threads) {
+            Ok(n) => info!(log, "wrote {} records to {}", n, bam_file),
+            Err(e) => {
+                crit!(log, "couldn't convert {} to BAM: {}", rad_file, e);
+                drop(log);
+                std::process::exit(1);
+            }
+        }
     }
 
-    if let Some(ref t) = opts.subcommand_matches("filter") {
+    if let Some(t) = opts.subcommand_matches("filter") {
         let in_bam_file: String = t.value_of_t("ibam").unwrap();
         let out_bam_file: String = t.value_of_t("obam").unwrap();
         let txplen_file: String = t.value_of_t("txplen").unwrap();
@@ -84,4 +292,270 @@ fn main() {
         arms::filter::filter_bam(&in_bam_file, &out_bam_file, &txplen_file, num_threads,fltr_unsplcd, &log)
     }
 
+    if let Some(t) = opts.subcommand_matches("rad") {
+        if let Err(msg) = rad(t, &log) {
+            crit!(log, "{}", msg);
+            // make sure the messages are written before exiting
+            drop(log);
+            std::process::exit(1);
+        }
+    }
+
+}
+
+fn convert(t: &clap::ArgMatches, log: &slog::Logger) -> Result<(), String> {
+    let input_file: String = t.value_of_t("bam").unwrap();
+    let mut rad_file: String = t.value_of_t("output").unwrap();
+    if t.is_present("alevin-fry") && rad_file != "-" {
+        rad_file = std::path::Path::new(&rad_file)
+            .join(arms::metadata::RAD_FILE)
+            .to_string_lossy()
+            .into_owned();
+    }
+    let num_threads: u32 = t.value_of_t("threads").unwrap();
+    let opts = arms::convert::ConvertOpts {
+        paired: t.is_present("paired"),
+        mate_positions: t.is_present("mate-pos"),
+        positions: t.is_present("pos"),
+        frag_len: t.is_present("frag-len"),
+        dist_3p: t.is_present("dist-3p"),
+        index: t.is_present("index"),
+        compress: t.is_present("compress"),
+        ambiguous: match t.value_of("ambiguous-bases").unwrap() {
+            "map" => arms::convert::AmbiguousBases::Map,
+            _ => arms::convert::AmbiguousBases::Reject,
+        },
+        cb_tags: tag_list(t.value_of("cb-tag").unwrap()),
+        umi_tags: tag_list(t.value_of("umi-tag").unwrap()),
+        n_bases: match t.value_of("n-bases").unwrap() {
+            "drop" => arms::convert::NBases::Drop,
+            "expand" => arms::convert::NBases::Expand,
+            _ => arms::convert::NBases::Replace,
+        },
+        whitelist: t.value_of("whitelist").map(std::path::PathBuf::from),
+        require_flags: flag_mask(t.value_of("require-flags").unwrap())?,
+        exclude_flags: flag_mask(t.value_of("exclude-flags").unwrap())?,
+        dedup_tids: t.is_present("dedup"),
+        sort_tids: t.is_present("sort-tids"),
+        report: t.value_of("report").map(std::path::PathBuf::from),
+        alevin_fry: t.is_present("alevin-fry"),
+    };
+    arms::convert::bam2rad(input_file, rad_file, num_threads, &opts, log).map(|_| ())
+}
+
+// the tag names of a comma-separated list such as "UB,UR"
+fn tag_list(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+// a SAM flag mask in decimal or, with 0x, in hexadecimal, as samtools takes them
+fn flag_mask(mask: &str) -> Result<u16, String> {
+    let parsed = match mask.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => mask.parse(),
+    };
+    parsed.map_err(|_| format!("{:?} is not a SAM flag mask", mask))
+}
+
+// the value of a numeric option, which clap leaves as a string
+fn number_of<T: std::str::FromStr>(
+    m: &clap::ArgMatches,
+    name: &str,
+    what: &str,
+) -> Result<T, String> {
+    let n = m.value_of(name).unwrap_or_default();
+    n.parse()
+        .map_err(|_| format!("{:?} is not a number of {}", n, what))
+}
+
+fn create_rad_file(rad_file: &str) -> Result<std::io::BufWriter<std::fs::File>, String> {
+    std::fs::File::create(rad_file)
+        .map(std::io::BufWriter::new)
+        .map_err(|e| format!("couldn't create output RAD file {}: {}", rad_file, e))
+}
+
+fn open_rad_file(
+    rad_file: &str,
+) -> Result<arms::RadReader<std::io::BufReader<std::fs::File>>, String> {
+    arms::RadReader::from_path(rad_file)
+        .map_err(|e| format!("couldn't read RAD file {}: {}", rad_file, e))
+}
+
+// like `open_rad_file`, but "-" reads from stdin
+fn open_rad_input(rad_file: &str) -> Result<arms::RadReader<Box<dyn std::io::Read>>, String> {
+    let input: Box<dyn std::io::Read> = if rad_file == "-" {
+        Box::new(std::io::BufReader::with_capacity(1048576, std::io::stdin()))
+    } else {
+        std::fs::File::open(rad_file)
+            .map(|f| Box::new(std::io::BufReader::with_capacity(1048576, f)))
+            .map_err(|e| format!("couldn't read RAD file {}: {}", rad_file, e))?
+    };
+    arms::RadReader::new(input).map_err(|e| format!("couldn't read RAD file {}: {}", rad_file, e))
+}
+
+// run a `rad` subcommand, returning the message to log if it fails
+fn rad(t: &clap::ArgMatches, log: &slog::Logger) -> Result<(), String> {
+    if let Some(v) = t.subcommand_matches("view") {
+        let rad_file: String = v.value_of_t("input").unwrap();
+        let format = match v.value_of("format").unwrap() {
+            "json" => arms::view::ViewFormat::Json,
+            _ => arms::view::ViewFormat::Tsv,
+        };
+        let head = match v.value_of("head") {
+            Some(n) => Some(
+                n.parse::<u64>()
+                    .map_err(|_| format!("{:?} is not a number of reads", n))?,
+            ),
+            None => None,
+        };
+        let rdr = open_rad_input(&rad_file)?;
+        let stdout = std::io::stdout();
+        let mut out = std::io::BufWriter::new(stdout.lock());
+        match arms::view::view(rdr, format, head, &mut out) {
+            // stop quietly when the output is closed, e.g. by `head`
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+                return Err(format!("couldn't view {}: {}", rad_file, e));
+            }
+            _ => (),
+        }
+    }
+    if let Some(v) = t.subcommand_matches("stats") {
+        let rad_file: String = v.value_of_t("input").unwrap();
+        let format = match v.value_of("format").unwrap() {
+            "json" => arms::stats::StatsFormat::Json,
+            _ => arms::stats::StatsFormat::Text,
+        };
+        let top = number_of(v, "top", "references")?;
+        let rdr = open_rad_input(&rad_file)?;
+        let stdout = std::io::stdout();
+        let mut out = std::io::BufWriter::new(stdout.lock());
+        arms::stats::stats(rdr, format, top, &mut out)
+            .map_err(|e| format!("couldn't compute the statistics of {}: {}", rad_file, e))?;
+    }
+    if let Some(v) = t.subcommand_matches("merge") {
+        let rad_file: String = v.value_of_t("output").unwrap();
+        let inputs: Vec<&str> = v.values_of("input").unwrap().collect();
+        let owriter = create_rad_file(&rad_file)?;
+        let num_chunks = arms::merge::merge_rad(&inputs, owriter, v.is_present("remap"))
+            .map_err(|e| format!("couldn't merge into {}: {}", rad_file, e))?;
+        info!(
+            log,
+            "merged {} files into {} ({} chunks)",
+            inputs.len(),
+            rad_file,
+            num_chunks
+        );
+    }
+    if let Some(v) = t.subcommand_matches("subset") {
+        let in_file: String = v.value_of_t("input").unwrap();
+        let rad_file: String = v.value_of_t("output").unwrap();
+        let barcode_file: String = v.value_of_t("barcodes").unwrap();
+        let rdr = open_rad_input(&in_file)?;
+        let cblen = rdr
+            .file_tag_value("cblen")
+            .and_then(|l| l.as_u64())
+            .map(|l| l as usize);
+        let barcodes = arms::subset::read_barcode_list(&barcode_file, cblen)
+            .map_err(|e| format!("couldn't read barcode list {}: {}", barcode_file, e))?;
+        let owriter = create_rad_file(&rad_file)?;
+        let (num_kept, num_reads) = arms::subset::subset_rad(rdr, &barcodes, owriter)
+            .map_err(|e| format!("couldn't subset {}: {}", in_file, e))?;
+        info!(
+            log,
+            "kept {} of {} reads from {} barcodes",
+            num_kept,
+            num_reads,
+            barcodes.len()
+        );
+    }
+    if let Some(v) = t.subcommand_matches("split") {
+        let in_file: String = v.value_of_t("input").unwrap();
+        let prefix: String = v.value_of_t("output").unwrap();
+        let parts = number_of(v, "parts", "parts")?;
+        let rdr = open_rad_input(&in_file)?;
+        let owriters = (0..parts)
+            .map(|part| create_rad_file(&format!("{}.{}.rad", prefix, part)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let counts = arms::subset::split_rad(rdr, owriters)
+            .map_err(|e| format!("couldn't split {}: {}", in_file, e))?;
+        for (part, n) in counts.iter().enumerate() {
+            info!(log, "{}.{}.rad: {} reads", prefix, part, n);
+        }
+    }
+    if let Some(v) = t.subcommand_matches("index") {
+        let rad_file: String = v.value_of_t("input").unwrap();
+        let index_file = arms::index::index_path(&rad_file);
+        let index = open_rad_file(&rad_file)?;
+        let index = arms::index::RadIndex::build(index)
+            .and_then(|index| index.to_path(&index_file).map(|_| index))
+            .map_err(|e| format!("couldn't index {}: {}", rad_file, e))?;
+        info!(
+            log,
+            "wrote the index of {} chunks to {}",
+            index.num_chunks(),
+            index_file.display()
+        );
+    }
+    if let Some(v) = t.subcommand_matches("collate") {
+        let in_file: String = v.value_of_t("input").unwrap();
+        let rad_file: String = v.value_of_t("output").unwrap();
+        let cblen = open_rad_file(&in_file)?
+            .file_tag_value("cblen")
+            .and_then(|l| l.as_u64())
+            .map(|l| l as usize);
+        let permit = match v.value_of("permit") {
+            Some(f) => Some(
+                arms::subset::read_barcode_list(f, cblen)
+                    .map_err(|e| format!("couldn't read permit list {}: {}", f, e))?,
+            ),
+            None => None,
+        };
+        let corrections = match v.value_of("corrections") {
+            Some(f) => Some(
+                arms::collate::read_barcode_map(f, cblen)
+                    .map_err(|e| format!("couldn't read barcode map {}: {}", f, e))?,
+            ),
+            None => None,
+        };
+        let tmp_dir = match v.value_of("tmp-dir") {
+            Some(d) => std::path::PathBuf::from(d),
+            None => std::path::Path::new(&rad_file)
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or_else(|| std::path::Path::new("."))
+                .to_path_buf(),
+        };
+        let opts = arms::collate::CollateOpts {
+            max_records: number_of(v, "max-records", "reads")?,
+            cells_per_chunk: number_of(v, "cells-per-chunk", "cells")?,
+            tmp_dir,
+            permit,
+            corrections,
+        };
+        let owriter = create_rad_file(&rad_file)?;
+        let s = arms::collate::collate_rad(&in_file, owriter, &opts)
+            .map_err(|e| format!("couldn't collate {}: {}", in_file, e))?;
+        info!(
+            log,
+            "wrote {} cells in {} chunks to {}", s.num_cells, s.num_chunks, rad_file
+        );
+        info!(
+            log,
+            "{} reads, {} with corrected barcodes, {} dropped as not permitted",
+            s.num_reads,
+            s.num_corrected,
+            s.num_dropped
+        );
+    }
+    if let Some(v) = t.subcommand_matches("validate") {
+        let rad_file: String = v.value_of_t("input").unwrap();
+        let repair = v.is_present("repair");
+        if !arms::validate::validate(&rad_file, repair, v.is_present("streamed"), log) {
+            return Err(format!("validation of {} failed", rad_file));
+        }
+    }
+    Ok(())
 }